@@ -0,0 +1,461 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    net::{Ipv4Addr, SocketAddr},
+    num::ParseIntError,
+    path::{Path, PathBuf},
+};
+
+use dhcproto::{
+    v4::{DhcpOption, Message, OptionCode},
+    Decodable, Decoder,
+};
+
+use crate::{
+    config::AclConfig,
+    packet::{format_mac, DHCPMessage},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Matcher {
+    Mac(Vec<u8>),
+    Oui([u8; 3]),
+    MacList {
+        path: PathBuf,
+        macs: HashSet<Vec<u8>>,
+        ouis: HashSet<[u8; 3]>,
+    },
+    ClientId(Vec<u8>),
+    VendorClass(Vec<u8>),
+    UserClass(Vec<u8>),
+}
+
+impl Matcher {
+    // `mac:aa:bb:cc:dd:ee:ff`, `oui:aa:bb:cc`, `file:/path/to/list`,
+    // `client-id:01:aa:bb:cc:dd:ee:ff`, `vendor-class:PXEClient`, `user-class:iPXE`
+    pub fn parse(s: &str) -> io::Result<Self> {
+        let (kind, value) = s
+            .split_once(':')
+            .ok_or_else(|| invalid(format!("matcher without kind: {}", s)))?;
+        match kind {
+            "mac" => Ok(Matcher::Mac(parse_hex(value)?)),
+            "oui" => Ok(Matcher::Oui(parse_oui(value)?)),
+            "file" => Matcher::load_list(value),
+            "client-id" => Ok(Matcher::ClientId(parse_hex(value)?)),
+            "vendor-class" => Ok(Matcher::VendorClass(value.as_bytes().to_vec())),
+            "user-class" => Ok(Matcher::UserClass(value.as_bytes().to_vec())),
+            _ => Err(invalid(format!("unknown matcher kind: {}", kind))),
+        }
+    }
+
+    // one MAC (exact) or OUI (3 octets) per line, `#` starts a comment
+    fn load_list<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let f = File::open(path.as_ref())?;
+        let mut macs = HashSet::new();
+        let mut ouis = HashSet::new();
+        for line in BufReader::new(f).lines() {
+            let line = line?;
+            let entry = line.split('#').next().unwrap_or_default().trim();
+            if entry.is_empty() {
+                continue;
+            }
+            let bytes = parse_hex(entry)?;
+            if bytes.len() == 3 {
+                ouis.insert([bytes[0], bytes[1], bytes[2]]);
+            } else {
+                macs.insert(bytes);
+            }
+        }
+        Ok(Matcher::MacList {
+            path: path.as_ref().to_path_buf(),
+            macs,
+            ouis,
+        })
+    }
+
+    pub fn matches(&self, msg: &DHCPMessage) -> bool {
+        let chaddr = msg.chaddr();
+        match self {
+            Matcher::Mac(mac) => chaddr == mac.as_slice(),
+            Matcher::Oui(oui) => chaddr.starts_with(oui),
+            Matcher::MacList { macs, ouis, .. } => {
+                macs.contains(chaddr)
+                    || (chaddr.len() >= 3 && ouis.contains(&[chaddr[0], chaddr[1], chaddr[2]]))
+            }
+            Matcher::ClientId(id) => msg.client_id() == Some(id.as_slice()),
+            Matcher::VendorClass(prefix) => msg
+                .vendor_class()
+                .map(|v| v.starts_with(prefix))
+                .unwrap_or(false),
+            Matcher::UserClass(class) => msg.user_classes().contains(&class.as_slice()),
+        }
+    }
+}
+
+impl fmt::Display for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Matcher::Mac(mac) => write!(f, "mac:{}", format_mac(mac)),
+            Matcher::Oui(oui) => write!(f, "oui:{}", format_mac(oui)),
+            Matcher::MacList { path, .. } => write!(f, "file:{}", path.display()),
+            Matcher::ClientId(id) => write!(f, "client-id:{}", format_mac(id)),
+            Matcher::VendorClass(v) => write!(f, "vendor-class:{}", String::from_utf8_lossy(v)),
+            Matcher::UserClass(v) => write!(f, "user-class:{}", String::from_utf8_lossy(v)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Class {
+    pub name: String,
+    pub upstream: Option<SocketAddr>,
+    matchers: Vec<Matcher>,
+    options: Vec<DhcpOption>,
+}
+
+impl Class {
+    // adds the class's options to a request before it is forwarded, replacing those the
+    // client sent. returns whether there were any.
+    pub fn apply(&self, msg: &mut Message) -> bool {
+        let opts = msg.opts_mut();
+        for opt in self.options.iter() {
+            opts.insert(opt.clone());
+        }
+        !self.options.is_empty()
+    }
+}
+
+#[derive(Debug)]
+pub enum Verdict<'a> {
+    Allow(Option<&'a Class>),
+    Deny(String),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    allow: Vec<Matcher>,
+    deny: Vec<Matcher>,
+    classes: Vec<Class>,
+}
+
+impl Acl {
+    pub fn new(config: &AclConfig) -> io::Result<Self> {
+        let parse_all = |v: &[String]| -> io::Result<Vec<Matcher>> {
+            v.iter().map(|s| Matcher::parse(s)).collect()
+        };
+        let mut classes = Vec::new();
+        for c in config.class.iter() {
+            classes.push(Class {
+                name: c.name.clone(),
+                upstream: c.upstream,
+                matchers: parse_all(&c.matches)?,
+                options: c
+                    .options
+                    .iter()
+                    .map(|s| parse_option(s))
+                    .collect::<io::Result<_>>()?,
+            });
+        }
+        Ok(Self {
+            allow: parse_all(&config.allow)?,
+            deny: parse_all(&config.deny)?,
+            classes,
+        })
+    }
+
+    // deny list wins over allow list; a non-empty allow list denies everything else.
+    // the first matching class labels the client.
+    pub fn check(&self, msg: &DHCPMessage) -> Verdict<'_> {
+        if let Some(m) = self.deny.iter().find(|m| m.matches(msg)) {
+            return Verdict::Deny(format!("deny list ({})", m));
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|m| m.matches(msg)) {
+            return Verdict::Deny(String::from("not in allow list"));
+        }
+        let class = self
+            .classes
+            .iter()
+            .find(|c| c.matchers.iter().any(|m| m.matches(msg)));
+        Verdict::Allow(class)
+    }
+}
+
+impl fmt::Display for Acl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for m in self.deny.iter() {
+            writeln!(f, "deny {}", m)?;
+        }
+        for m in self.allow.iter() {
+            writeln!(f, "allow {}", m)?;
+        }
+        for c in self.classes.iter() {
+            let matchers: Vec<_> = c.matchers.iter().map(|m| m.to_string()).collect();
+            write!(f, "class {} match={}", c.name, matchers.join(","))?;
+            if let Some(upstream) = c.upstream {
+                write!(f, " upstream={}", upstream)?;
+            }
+            if !c.options.is_empty() {
+                let codes: Vec<_> = c
+                    .options
+                    .iter()
+                    .map(|o| u8::from(OptionCode::from(o)).to_string())
+                    .collect();
+                write!(f, " options={}", codes.join(","))?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_hex(s: &str) -> io::Result<Vec<u8>> {
+    s.split([':', '-'])
+        .map(|v| u8::from_str_radix(v, 16).map_err(|e| invalid(format!("{}: {}", s, e))))
+        .collect()
+}
+
+// `<code>=<kind>:<value>`: `15=text:example.com`, `6=ip:10.0.0.53,10.0.0.54`,
+// `43=hex:01:04:00:00:00:01`, `23=u8:64`, `57=u16:1500`, `51=u32:3600`.
+// the value has to be valid for the option code.
+fn parse_option(s: &str) -> io::Result<DhcpOption> {
+    let (code, value) = s
+        .split_once('=')
+        .ok_or_else(|| invalid(format!("option without code: {}", s)))?;
+    let code: u8 = code
+        .trim()
+        .parse()
+        .map_err(|e| invalid(format!("{}: {}", s, e)))?;
+    // pad, message type and end belong to the relay
+    if matches!(code, 0 | 53 | 255) {
+        return Err(invalid(format!("option {} cannot be set: {}", code, s)));
+    }
+    let (kind, value) = value
+        .split_once(':')
+        .ok_or_else(|| invalid(format!("option value without kind: {}", s)))?;
+    let number = |e: ParseIntError| invalid(format!("{}: {}", s, e));
+    let data = match kind {
+        "text" => value.as_bytes().to_vec(),
+        "hex" => parse_hex(value)?,
+        "ip" => value
+            .split(',')
+            .map(|v| v.trim().parse::<Ipv4Addr>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| invalid(format!("{}: {}", s, e)))?
+            .iter()
+            .flat_map(|ip| ip.octets())
+            .collect(),
+        "u8" => vec![value.parse::<u8>().map_err(number)?],
+        "u16" => value.parse::<u16>().map_err(number)?.to_be_bytes().to_vec(),
+        "u32" => value.parse::<u32>().map_err(number)?.to_be_bytes().to_vec(),
+        _ => return Err(invalid(format!("unknown option kind: {}", kind))),
+    };
+    let len = u8::try_from(data.len())
+        .map_err(|_| invalid(format!("option longer than 255 bytes: {}", s)))?;
+    let mut encoded = vec![code, len];
+    encoded.extend(data);
+    DhcpOption::decode(&mut Decoder::new(&encoded)).map_err(|e| invalid(format!("{}: {}", s, e)))
+}
+
+fn parse_oui(s: &str) -> io::Result<[u8; 3]> {
+    match parse_hex(s)?.as_slice() {
+        &[a, b, c] => Ok([a, b, c]),
+        _ => Err(invalid(format!("OUI must be 3 octets: {}", s))),
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use dhcproto::v4::MessageType;
+
+    use super::*;
+    use crate::config::ClassConfig;
+
+    fn request(mac: [u8; 6], opts: Vec<DhcpOption>) -> DHCPMessage {
+        let unspecified = Ipv4Addr::UNSPECIFIED;
+        let mut msg = Message::new(unspecified, unspecified, unspecified, unspecified, &mac);
+        msg.opts_mut()
+            .insert(DhcpOption::MessageType(MessageType::Discover));
+        for opt in opts {
+            msg.opts_mut().insert(opt);
+        }
+        DHCPMessage::from(msg)
+    }
+
+    fn strings(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn is_denied(verdict: Verdict<'_>) -> bool {
+        matches!(verdict, Verdict::Deny(_))
+    }
+
+    const PHONE: [u8; 6] = [0x00, 0x0b, 0x82, 1, 2, 3];
+    const LAPTOP: [u8; 6] = [0x02, 0, 0, 0, 0, 1];
+
+    #[test]
+    fn matchers() {
+        let phone = request(PHONE, vec![]);
+        let pxe = request(
+            LAPTOP,
+            vec![
+                DhcpOption::ClassIdentifier(b"PXEClient:Arch:00007".to_vec()),
+                DhcpOption::UserClass(b"iPXE".to_vec()),
+                DhcpOption::ClientIdentifier(vec![1, 2, 0, 0, 0, 0, 1]),
+            ],
+        );
+        let cases = [
+            ("mac:00:0b:82:01:02:03", true, false),
+            ("mac:00-0b-82-01-02-03", true, false),
+            ("oui:00:0b:82", true, false),
+            ("client-id:01:02:00:00:00:00:01", false, true),
+            ("vendor-class:PXEClient", false, true),
+            ("vendor-class:PXEClient:Arch:00000", false, false),
+            ("user-class:iPXE", false, true),
+            ("user-class:iPX", false, false),
+        ];
+        for (s, on_phone, on_pxe) in cases {
+            let m = Matcher::parse(s).unwrap();
+            assert_eq!(m.matches(&phone), on_phone, "{}", s);
+            assert_eq!(m.matches(&pxe), on_pxe, "{}", s);
+        }
+        assert_eq!(
+            Matcher::parse("mac:00-0b-82-01-02-03").unwrap().to_string(),
+            "mac:00:0b:82:01:02:03"
+        );
+        for s in [
+            "00:0b:82",
+            "ip:10.0.0.1",
+            "mac:zz",
+            "oui:00:0b",
+            "client-id:pc-1",
+        ] {
+            assert!(Matcher::parse(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn mac_list() {
+        let path = std::env::temp_dir().join(format!("middle-sock-acl-{}", std::process::id()));
+        let mut f = File::create(&path).unwrap();
+        writeln!(f, "# phones\n00:0b:82 # whole vendor\n\n02:00:00:00:00:01").unwrap();
+        let m = Matcher::parse(&format!("file:{}", path.display()));
+        std::fs::remove_file(&path).unwrap();
+        let m = m.unwrap();
+        assert!(m.matches(&request(PHONE, vec![])));
+        assert!(m.matches(&request(LAPTOP, vec![])));
+        assert!(!m.matches(&request([0x02, 0, 0, 0, 0, 2], vec![])));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let acl = Acl::new(&AclConfig {
+            allow: strings(&["oui:00:0b:82"]),
+            deny: strings(&["mac:00:0b:82:01:02:03"]),
+            class: Vec::new(),
+        })
+        .unwrap();
+        assert!(is_denied(acl.check(&request(PHONE, vec![]))));
+        assert!(!is_denied(
+            acl.check(&request([0x00, 0x0b, 0x82, 1, 2, 4], vec![]))
+        ));
+        // not in the allow list
+        assert!(is_denied(acl.check(&request(LAPTOP, vec![]))));
+
+        // an empty allow list allows everything not denied
+        let acl = Acl::new(&AclConfig {
+            deny: strings(&["mac:00:0b:82:01:02:03"]),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(!is_denied(acl.check(&request(LAPTOP, vec![]))));
+    }
+
+    #[test]
+    fn first_matching_class() {
+        let acl = Acl::new(&AclConfig {
+            class: vec![
+                ClassConfig {
+                    name: String::from("phones"),
+                    matches: strings(&["oui:00:0b:82"]),
+                    upstream: Some("10.0.0.2:67".parse().unwrap()),
+                    options: strings(&["15=text:phones.example.com", "6=ip:10.0.0.53,10.0.0.54"]),
+                },
+                ClassConfig {
+                    name: String::from("all-phones"),
+                    matches: strings(&["mac:00:0b:82:01:02:03"]),
+                    upstream: None,
+                    options: Vec::new(),
+                },
+            ],
+            ..Default::default()
+        })
+        .unwrap();
+        let Verdict::Allow(Some(class)) = acl.check(&request(PHONE, vec![])) else {
+            panic!("phone did not match a class");
+        };
+        assert_eq!(class.name, "phones");
+        assert_eq!(class.upstream, Some("10.0.0.2:67".parse().unwrap()));
+        assert!(matches!(
+            acl.check(&request(LAPTOP, vec![])),
+            Verdict::Allow(None)
+        ));
+
+        let mut msg = request(PHONE, vec![DhcpOption::DomainName(String::from("x"))]).raw();
+        assert!(class.apply(&mut msg));
+        assert_eq!(
+            msg.opts().get(OptionCode::DomainName),
+            Some(&DhcpOption::DomainName(String::from("phones.example.com")))
+        );
+        assert_eq!(
+            msg.opts().get(OptionCode::DomainNameServer),
+            Some(&DhcpOption::DomainNameServer(vec![
+                Ipv4Addr::new(10, 0, 0, 53),
+                Ipv4Addr::new(10, 0, 0, 54)
+            ]))
+        );
+    }
+
+    #[test]
+    fn parse_options() {
+        let cases = [
+            ("23=u8:64", DhcpOption::DefaultIpTtl(64)),
+            ("57=u16:1500", DhcpOption::MaxMessageSize(1500)),
+            ("51=u32:3600", DhcpOption::AddressLeaseTime(3600)),
+            (
+                "3=ip:10.0.0.1",
+                DhcpOption::Router(vec![Ipv4Addr::new(10, 0, 0, 1)]),
+            ),
+            (
+                "43=hex:01:04:00:00:00:01",
+                DhcpOption::VendorExtensions(vec![1, 4, 0, 0, 0, 1]),
+            ),
+        ];
+        for (s, opt) in cases {
+            assert_eq!(parse_option(s).unwrap(), opt, "{}", s);
+        }
+        for s in [
+            "0=u8:1",
+            "53=u8:1",
+            "255=u8:1",
+            "256=u8:1",
+            "23",
+            "23=64",
+            "23=float:6.4",
+            "23=u8:256",
+            "3=ip:10.0.0",
+            "51=u8:1",
+        ] {
+            assert!(parse_option(s).is_err(), "{}", s);
+        }
+        let long = format!("15=text:{}", "a".repeat(256));
+        assert!(parse_option(&long).is_err());
+    }
+}
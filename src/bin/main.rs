@@ -1,59 +1,174 @@
 use std::{
     env, error, io,
     net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
 };
 
-use clap::Parser;
-use middle_sock::{init_routeinfo_map, new_route, run_process, setup_ns, socket::Socket};
+use clap::{Parser, Subcommand};
+use middle_sock::{
+    config::Config, ctl, dns, helper, metrics, plan::Plan, proxy, run_process, socket::Socket,
+    state::State, telemetry, watch,
+};
+use tokio::signal::unix::{signal, SignalKind};
 
 #[derive(Debug, Parser)]
 struct Cli {
-    #[arg(short, long, help = "command middle-sock executes")]
-    command: String,
+    #[arg(
+        short,
+        long,
+        help = "command middle-sock executes (optional when `network.netns` is not middle-sock's)"
+    )]
+    command: Option<String>,
+    #[arg(long, help = "path to config file (TOML)")]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    subcommand: Option<Sub>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Sub {
+    #[command(about = "send a command to a running middle-sock (`help` lists commands)")]
+    Ctl {
+        #[arg(short, long, default_value = ctl::DEFAULT_SOCKET, help = "control socket path")]
+        socket: PathBuf,
+        #[arg(required = true, num_args = 1..)]
+        args: Vec<String>,
+    },
+    #[command(
+        about = "print the network changes and the command middle-sock would run, without making them"
+    )]
+    Plan {
+        #[arg(long, help = "print the plan as JSON")]
+        json: bool,
+    },
 }
 
 fn main() -> Result<(), Box<dyn error::Error>> {
     env_logger::init();
-    let server_host = env::var("SERVER_HOST")
-        .expect("no data in `SERVER_HOST`")
-        .parse::<SocketAddr>()
-        .expect("could not parse `SERVER_HOST`");
 
     let cli = Cli::parse();
 
-    let route_file = "/mnt/route";
-
-    let route = new_route(route_file)?;
-    let mut route_info = init_routeinfo_map();
-
-    for r in route {
-        r.parse_network(&mut route_info)?;
+    if let Some(Sub::Ctl { socket, args }) = cli.subcommand {
+        let rt = tokio::runtime::Runtime::new()?;
+        let response = rt.block_on(ctl::request(socket, &args.join(" ")))?;
+        print!("{}", response);
+        return Ok(());
     }
 
-    let ns_name = "dhcp";
-    let link_name = "veth0";
+    let server_host = env::var("SERVER_HOST")
+        .expect("no data in `SERVER_HOST`")
+        .parse::<SocketAddr>()
+        .expect("could not parse `SERVER_HOST`");
+
+    let config = match &cli.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
 
     let ip = match server_host.ip() {
         IpAddr::V4(v) => v,
-        IpAddr::V6(_) => todo!(),
+        IpAddr::V6(_) => return Err("IPv6 `SERVER_HOST` is not supported".into()),
     };
 
-    for (k, v) in route_info.iter() {
-        if !v.is_full() {
-            continue;
+    if let Some(Sub::Plan { json }) = cli.subcommand {
+        let plan = Plan::new(&config, ip, cli.command.as_deref())?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&plan)?);
+        } else {
+            print!("{}", plan);
         }
-        setup_ns(link_name, k, ns_name, ip, v)?
+        return Ok(());
+    }
+
+    // the DHCP server in a namespace that is not ours is run by whoever owns it
+    if cli.command.is_none() && config.network.owns_netns() {
+        return Err("`--command` is required".into());
     }
 
-    let cmd = cli.command;
+    // first, while this is the only thread: setup_ns and later syncs run their steps in it
+    helper::spawn()?;
 
-    run_process(cmd, ns_name.to_string())?;
+    let state = Arc::new(State::new(cli.config.clone(), config.clone(), ip)?);
 
+    // the exporter runs on main_rt; setup_ns below creates runtimes of its own,
+    // so the context is only entered while installing it
     let main_rt = tokio::runtime::Runtime::new()?;
-    main_rt.block_on(async {
-        let sock = Socket::new_without_domain().await?;
-        sock.listen(server_host).await?;
+    {
+        let _rt = main_rt.enter();
+        telemetry::init(&config.tracing)?;
+    }
+
+    watch::sync(&state, ip)?;
+
+    let ns_name = config.network.netns.as_str();
+
+    match cli.command {
+        Some(cmd) => state.set_child(run_process(cmd, ns_name.to_string())?),
+        None => log::info!("no command given; not starting a server in {}", ns_name),
+    }
+
+    let res = main_rt.block_on(async {
+        if let Some(addr) = config.metrics.listen {
+            tokio::spawn(async move {
+                if let Err(e) = metrics::serve(addr).await {
+                    log::error!("metrics endpoint stopped: {}", e);
+                }
+            });
+        }
+        let ctl_socket = config
+            .ctl
+            .socket
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(ctl::DEFAULT_SOCKET));
+        let ctl_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(e) = ctl::serve(ctl_socket, ctl_state).await {
+                log::error!("control socket stopped: {}", e);
+            }
+        });
+        if let Some(server_id) = config.proxy_dhcp.server_id {
+            let proxy_state = Arc::clone(&state);
+            tokio::spawn(async move {
+                if let Err(e) = proxy::serve(server_id, proxy_state).await {
+                    log::error!("proxyDHCP responder stopped: {}", e);
+                }
+            });
+        }
+        tokio::spawn(dns::watch(Arc::clone(&state)));
+        tokio::spawn(watch::watch(Arc::clone(&state), ip));
+        let reload_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(s) => s,
+                Err(e) => {
+                    log::error!("could not install SIGHUP handler: {}", e);
+                    return;
+                }
+            };
+            while hangup.recv().await.is_some() {
+                log::info!("SIGHUP received; reloading");
+                let state = Arc::clone(&reload_state);
+                match tokio::task::spawn_blocking(move || state.reload()).await {
+                    Ok(Ok(changes)) if changes.is_empty() => log::info!("reload: no changes"),
+                    Ok(Ok(changes)) => log::info!("reload: {}", changes.join(", ")),
+                    Ok(Err(e)) => log::error!("reload failed: {}", e),
+                    Err(e) => log::error!("reload failed: {}", e),
+                }
+            }
+        });
+        let mut sock = Socket::new_without_domain().await?;
+        sock.set_state(state);
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            res = sock.listen(server_host) => res?,
+            _ = tokio::signal::ctrl_c() => log::info!("SIGINT received; exiting"),
+            _ = terminate.recv() => log::info!("SIGTERM received; exiting"),
+        }
         Ok::<(), io::Error>(())
-    })?;
+    });
+    // flush the spans still queued, also when the relay stopped with an error
+    telemetry::shutdown();
+    res?;
     Ok(())
 }
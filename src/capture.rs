@@ -0,0 +1,337 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+
+use crate::{
+    config::CaptureConfig,
+    metrics::{metrics, CAPTURE_ERRORS},
+    packet::{format_mac, DHCPMessage},
+};
+
+// pcapng block types and options
+// ref: https://www.ietf.org/archive/id/draft-ietf-opsawg-pcapng-01.html
+const SHB: u32 = 0x0A0D_0D0A;
+const IDB: u32 = 0x0000_0001;
+const EPB: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const IF_NAME: u16 = 2;
+const EPB_FLAGS: u16 = 2;
+// raw IPv4/IPv6, so Wireshark dissects UDP (and DHCP) from our synthesized headers
+const LINKTYPE_RAW: u16 = 101;
+const SNAPLEN: u32 = 65535;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl Direction {
+    fn flags(&self) -> u32 {
+        match self {
+            Direction::Inbound => 0b01,
+            Direction::Outbound => 0b10,
+        }
+    }
+}
+
+// one relayed datagram in the form it was received or sent
+#[derive(Debug)]
+pub struct Record<'a> {
+    pub direction: Direction,
+    // "received" or "transformed"
+    pub form: &'a str,
+    pub iface: Option<&'a str>,
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub data: &'a [u8],
+}
+
+#[derive(Debug)]
+struct Writer {
+    file: File,
+    written: u64,
+}
+
+#[derive(Debug)]
+pub struct Capture {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    macs: Vec<Vec<u8>>,
+    types: Vec<String>,
+    writer: Mutex<Option<Writer>>,
+}
+
+impl Capture {
+    pub fn new<P: AsRef<Path>>(path: P, config: &CaptureConfig) -> io::Result<Self> {
+        let macs = config
+            .mac
+            .iter()
+            .map(|m| {
+                m.split([':', '-'])
+                    .map(|v| u8::from_str_radix(v, 16))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let capture = Self {
+            path: path.as_ref().to_path_buf(),
+            max_size: config.max_size,
+            max_files: config.max_files.max(1),
+            macs,
+            types: config.types.iter().map(|t| t.to_lowercase()).collect(),
+            writer: Mutex::new(None),
+        };
+        *capture.writer.lock().unwrap() = Some(capture.open()?);
+        info!("capturing to {}", capture.path.display());
+        Ok(capture)
+    }
+
+    fn open(&self) -> io::Result<Writer> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        let mut header = section_header();
+        header.extend(interface_description("middle-sock"));
+        file.write_all(&header)?;
+        Ok(Writer {
+            file,
+            written: header.len() as u64,
+        })
+    }
+
+    // <path> -> <path>.1 -> ... -> <path>.<max_files - 1>
+    fn rotate(&self) -> io::Result<Writer> {
+        for i in (1..self.max_files).rev() {
+            let from = if i == 1 {
+                self.path.clone()
+            } else {
+                rotated(&self.path, i - 1)
+            };
+            if from.exists() {
+                fs::rename(&from, rotated(&self.path, i))?;
+            }
+        }
+        self.open()
+    }
+
+    pub fn matches(&self, msg: &DHCPMessage) -> bool {
+        (self.macs.is_empty() || self.macs.iter().any(|m| m.as_slice() == msg.chaddr()))
+            && (self.types.is_empty() || self.types.contains(&msg.msg_type_name()))
+    }
+
+    pub fn write(&self, msg: &DHCPMessage, record: &Record) {
+        if !self.matches(msg) {
+            return;
+        }
+        let comment = format!(
+            "direction={} form={} iface={} chaddr={} type={}",
+            match record.direction {
+                Direction::Inbound => "in",
+                Direction::Outbound => "out",
+            },
+            record.form,
+            record.iface.unwrap_or("-"),
+            format_mac(msg.chaddr()),
+            msg.msg_type_name(),
+        );
+        let block = enhanced_packet(record, &comment);
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(e) = self.append(&mut writer, &block) {
+            warn!("(capture) could not write {}: {}", self.path.display(), e);
+            metrics().inc(CAPTURE_ERRORS, &[("op", "write")]);
+        }
+    }
+
+    fn append(&self, writer: &mut Option<Writer>, block: &[u8]) -> io::Result<()> {
+        let full = writer
+            .as_ref()
+            .map(|w| self.max_size > 0 && w.written + block.len() as u64 > self.max_size)
+            .unwrap_or(true);
+        if full {
+            // a full writer would fail the same rotation on every packet; starting the
+            // file over keeps capturing within `max-size` until a rotation works again
+            let next = self.rotate().or_else(|e| {
+                warn!(
+                    "(capture) could not rotate {}: {}; starting it over",
+                    self.path.display(),
+                    e
+                );
+                metrics().inc(CAPTURE_ERRORS, &[("op", "rotate")]);
+                self.open()
+            });
+            match next {
+                Ok(next) => *writer = Some(next),
+                // left empty if the file cannot be opened either; the next packet retries
+                Err(e) => {
+                    *writer = None;
+                    return Err(e);
+                }
+            }
+        }
+        if let Some(w) = writer.as_mut() {
+            w.file.write_all(block)?;
+            w.written += block.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+fn rotated(path: &Path, i: usize) -> PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(format!(".{}", i));
+    PathBuf::from(s)
+}
+
+fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+    let len = (12 + body.len()) as u32;
+    let mut out = Vec::with_capacity(len as usize);
+    out.extend(block_type.to_le_bytes());
+    out.extend(len.to_le_bytes());
+    out.extend(body);
+    out.extend(len.to_le_bytes());
+    out
+}
+
+fn option(out: &mut Vec<u8>, code: u16, value: &[u8]) {
+    out.extend(code.to_le_bytes());
+    out.extend((value.len() as u16).to_le_bytes());
+    out.extend(value);
+    pad(out);
+}
+
+fn pad(out: &mut Vec<u8>) {
+    out.resize(out.len().next_multiple_of(4), 0);
+}
+
+fn section_header() -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend(BYTE_ORDER_MAGIC.to_le_bytes());
+    body.extend(1u16.to_le_bytes());
+    body.extend(0u16.to_le_bytes());
+    body.extend((-1i64).to_le_bytes());
+    block(SHB, &body)
+}
+
+fn interface_description(name: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend(LINKTYPE_RAW.to_le_bytes());
+    body.extend(0u16.to_le_bytes());
+    body.extend(SNAPLEN.to_le_bytes());
+    option(&mut body, IF_NAME, name.as_bytes());
+    option(&mut body, OPT_ENDOFOPT, &[]);
+    block(IDB, &body)
+}
+
+fn enhanced_packet(record: &Record, comment: &str) -> Vec<u8> {
+    let packet = ipv4_udp(record.src, record.dst, record.data);
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let mut body = Vec::new();
+    body.extend(0u32.to_le_bytes());
+    body.extend(((ts >> 32) as u32).to_le_bytes());
+    body.extend((ts as u32).to_le_bytes());
+    body.extend((packet.len() as u32).to_le_bytes());
+    body.extend((packet.len() as u32).to_le_bytes());
+    body.extend(&packet);
+    pad(&mut body);
+    option(&mut body, OPT_COMMENT, comment.as_bytes());
+    option(
+        &mut body,
+        EPB_FLAGS,
+        &record.direction.flags().to_le_bytes(),
+    );
+    option(&mut body, OPT_ENDOFOPT, &[]);
+    block(EPB, &body)
+}
+
+// synthesizes IPv4 + UDP headers around a payload (UDP checksum left as 0)
+fn ipv4_udp(src: SocketAddr, dst: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let v4 = |a: SocketAddr| match a.ip() {
+        IpAddr::V4(v) => v,
+        IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+    };
+    let udp_len = (8 + data.len()) as u16;
+    let total_len = 20 + udp_len;
+    let mut header = vec![0x45, 0];
+    header.extend(total_len.to_be_bytes());
+    header.extend([0, 0, 0x40, 0, 64, 17, 0, 0]);
+    header.extend(v4(src).octets());
+    header.extend(v4(dst).octets());
+    let checksum = checksum(&header);
+    header[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    let mut out = header;
+    out.extend(src.port().to_be_bytes());
+    out.extend(dst.port().to_be_bytes());
+    out.extend(udp_len.to_be_bytes());
+    out.extend([0, 0]);
+    out.extend(data);
+    out
+}
+
+fn checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|c| u32::from(u16::from_be_bytes([c[0], c[1]])))
+        .sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use dhcproto::v4::{DhcpOption, Message, MessageType};
+
+    use super::*;
+
+    #[test]
+    fn keeps_capturing_when_rotation_fails() {
+        let dir = std::env::temp_dir().join(format!("middle-sock-capture-{}", std::process::id()));
+        let path = dir.join("relay.pcapng");
+        // a non-empty directory in the way of `<path>.1` fails the rename
+        fs::create_dir_all(rotated(&path, 1).join("busy")).unwrap();
+        let config = CaptureConfig {
+            max_size: 1,
+            max_files: 2,
+            ..Default::default()
+        };
+        let capture = Capture::new(&path, &config).unwrap();
+
+        let mut msg = Message::default();
+        msg.opts_mut()
+            .insert(DhcpOption::MessageType(MessageType::Discover));
+        let msg = DHCPMessage::from(msg);
+        let record = Record {
+            direction: Direction::Inbound,
+            form: "received",
+            iface: None,
+            src: "10.0.0.1:68".parse().unwrap(),
+            dst: "10.0.0.2:67".parse().unwrap(),
+            data: &[0; 300],
+        };
+        capture.write(&msg, &record);
+        capture.write(&msg, &record);
+        let len = fs::metadata(&path).map(|m| m.len());
+        fs::remove_dir_all(&dir).unwrap();
+
+        let header = section_header().len() + interface_description("middle-sock").len();
+        assert!(len.unwrap() > header as u64);
+    }
+}
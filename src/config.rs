@@ -0,0 +1,462 @@
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use ipnet::Ipv4Net;
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Config {
+    pub acl: AclConfig,
+    pub metrics: MetricsConfig,
+    pub ctl: CtlConfig,
+    pub network: NetworkConfig,
+    pub capture: CaptureConfig,
+    pub event_log: EventLogConfig,
+    pub tracing: TracingConfig,
+    pub malformed: MalformedConfig,
+    pub pxe: PxeConfig,
+    pub proxy_dhcp: ProxyDhcpConfig,
+    pub dns: DnsConfig,
+    pub route_options: RouteOptionsConfig,
+    pub nat: NatConfig,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let s = fs::read_to_string(path)?;
+        toml::from_str(&s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+// matcher syntax: see `acl::Matcher::parse`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct AclConfig {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    pub class: Vec<ClassConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ClassConfig {
+    pub name: String,
+    #[serde(rename = "match")]
+    pub matches: Vec<String>,
+    #[serde(default)]
+    pub upstream: Option<SocketAddr>,
+    // added to the class's requests; syntax: see `acl::parse_option`
+    #[serde(default)]
+    pub options: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct MetricsConfig {
+    // e.g. "127.0.0.1:9167"; the endpoint is disabled when unset
+    pub listen: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct CtlConfig {
+    // defaults to `ctl::DEFAULT_SOCKET`
+    pub socket: Option<PathBuf>,
+    // members may use the socket too; otherwise only its owner (root) can
+    pub group: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct NetworkConfig {
+    pub route_file: PathBuf,
+    pub route_source: RouteSource,
+    // follow changes of the route source at runtime
+    pub watch: bool,
+    pub netns: Netns,
+    // add a named namespace when it does not exist; other namespaces must exist
+    pub create_netns: bool,
+    pub link: String,
+    pub peer: PeerAddress,
+    pub routes: NsRoutesConfig,
+    // how interfaces are attached, with overrides per interface
+    pub backend: LinkBackend,
+    pub backends: HashMap<String, LinkBackend>,
+    pub link_properties: LinkProperties,
+    // 802.1Q sub-interfaces relayed as interfaces of their own
+    pub vlans: Vec<VlanConfig>,
+}
+
+// routes inside the namespace, via the host side of the veth pair (or the interface's
+// routers with the other backends)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct NsRoutesConfig {
+    // a default route, with the metric of the host's default route on the interface
+    pub default: bool,
+    // the interface's other subnets and its routes via a gateway, where relayed clients are
+    pub clients: bool,
+    // policy routing: the routes go into this table, looked up for traffic from the server
+    pub table: Option<u32>,
+}
+
+impl Default for NsRoutesConfig {
+    fn default() -> Self {
+        Self {
+            default: true,
+            clients: true,
+            table: None,
+        }
+    }
+}
+
+// set on the links created for an interface (both ends of a veth pair, macvlan/ipvlan
+// children) before they are brought up
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct LinkProperties {
+    // defaults to the MTU of the interface's route, then to the interface's own
+    pub mtu: Option<u32>,
+    pub txqueuelen: Option<u32>,
+    // a locally administered MAC derived from the namespace and link name, so it is the
+    // same after a restart; not for ipvlan, which shares the parent's
+    pub stable_mac: bool,
+    // the links are labelled "<alias>:<interface>"; empty for no alias
+    pub alias: String,
+}
+
+impl Default for LinkProperties {
+    fn default() -> Self {
+        Self {
+            mtu: None,
+            txqueuelen: None,
+            stable_mac: true,
+            alias: String::from("middle-sock"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct VlanConfig {
+    pub parent: String,
+    pub id: u16,
+    // defaults to "<parent>.<id>"; created when missing, used as is otherwise
+    #[serde(default)]
+    pub name: Option<String>,
+    // relay address on the VLAN, sent as giaddr; the subnet is the VLAN's connected subnet
+    pub address: Ipv4Net,
+    // router of the VLAN, the default route of its RouteInfo
+    #[serde(default)]
+    pub gateway: Option<Ipv4Addr>,
+    // option 82 agent circuit ID; defaults to the sub-interface name
+    #[serde(default)]
+    pub circuit_id: Option<String>,
+    // server for clients on the VLAN; `SERVER_HOST` when unset
+    #[serde(default)]
+    pub upstream: Option<SocketAddr>,
+}
+
+impl VlanConfig {
+    pub fn name(&self) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| format!("{}.{}", self.parent, self.id))
+    }
+
+    pub fn circuit_id(&self) -> String {
+        self.circuit_id.clone().unwrap_or_else(|| self.name())
+    }
+}
+
+impl NetworkConfig {
+    // middle-sock adds the namespace when missing; it never deletes one
+    pub fn owns_netns(&self) -> bool {
+        self.netns.is_named() && self.create_netns
+    }
+
+    pub fn backend_for(&self, iface: &str) -> &LinkBackend {
+        self.backends.get(iface).unwrap_or(&self.backend)
+    }
+
+    pub fn vlan(&self, iface: &str) -> Option<&VlanConfig> {
+        self.vlans.iter().find(|v| v.name() == iface)
+    }
+
+    // the route table to read
+    pub fn route_path(&self) -> &Path {
+        match self.route_source {
+            RouteSource::File => &self.route_file,
+            RouteSource::Netlink => Path::new(PROC_ROUTE),
+        }
+    }
+}
+
+const PROC_ROUTE: &str = "/proc/net/route";
+
+// the namespace the DHCP server runs in, as the name or path the netlink helpers take:
+// "dhcp" (under /run/netns), a path such as "/proc/<pid>/ns/net", or "pid:<pid>" for the
+// namespace of a running process (e.g. a container started by another orchestrator)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Netns(String);
+
+impl Netns {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_named(&self) -> bool {
+        !self.0.starts_with('/')
+    }
+}
+
+impl TryFrom<String> for Netns {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.split_once(':') {
+            Some(("pid", pid)) => pid
+                .parse::<u32>()
+                .map(|pid| Netns(format!("/proc/{}/ns/net", pid)))
+                .map_err(|_| format!("invalid netns pid: {}", pid)),
+            None if s.starts_with('/') || !(s.is_empty() || s.contains('/')) => Ok(Netns(s)),
+            _ => Err(format!("invalid netns: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Netns {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RouteSource {
+    // `route-file`, a copy of the host's /proc/net/route; watched with inotify
+    File,
+    // the table of the namespace middle-sock runs in (e.g. with host networking);
+    // watched with netlink notifications
+    Netlink,
+}
+
+// address of the host side of the veth pair; the namespace side gets the server address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum PeerAddress {
+    // "first": the first host of the subnet (network + 1)
+    First,
+    // "auto": a host of the subnet not used by the host's addresses, neighbours or gateway
+    Auto,
+    // "link-local/30", "link-local/31": a point-to-point pair in 169.254.0.0/16;
+    // the namespace side gets the other end and the host a route to the server
+    LinkLocal(u8),
+    // e.g. "192.168.1.250"; must be in the subnet
+    Explicit(Ipv4Addr),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum LinkBackend {
+    // "veth": a veth pair; the host side takes the peer address and middle-sock relays
+    Veth,
+    // "macvlan", "ipvlan": a child of the interface, moved into the namespace, so the
+    // DHCP server sees the L2 segment directly
+    Macvlan,
+    Ipvlan,
+    // "bridge:<name>": a veth pair whose host side is a port of an existing bridge
+    Bridge(String),
+}
+
+impl TryFrom<String> for LinkBackend {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.split_once(':') {
+            None if s == "veth" => Ok(LinkBackend::Veth),
+            None if s == "macvlan" => Ok(LinkBackend::Macvlan),
+            None if s == "ipvlan" => Ok(LinkBackend::Ipvlan),
+            Some(("bridge", name)) if !name.is_empty() => Ok(LinkBackend::Bridge(name.to_string())),
+            _ => Err(format!("invalid link backend: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for LinkBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkBackend::Veth => write!(f, "veth"),
+            LinkBackend::Macvlan => write!(f, "macvlan"),
+            LinkBackend::Ipvlan => write!(f, "ipvlan"),
+            LinkBackend::Bridge(name) => write!(f, "bridge:{}", name),
+        }
+    }
+}
+
+impl TryFrom<String> for PeerAddress {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.as_str() {
+            "first" => Ok(PeerAddress::First),
+            "auto" => Ok(PeerAddress::Auto),
+            "link-local" | "link-local/30" => Ok(PeerAddress::LinkLocal(30)),
+            "link-local/31" => Ok(PeerAddress::LinkLocal(31)),
+            _ => s
+                .parse()
+                .map(PeerAddress::Explicit)
+                .map_err(|_| format!("invalid peer address: {}", s)),
+        }
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            route_file: PathBuf::from("/mnt/route"),
+            route_source: RouteSource::File,
+            watch: true,
+            netns: Netns(String::from("dhcp")),
+            create_netns: true,
+            link: String::from("veth0"),
+            peer: PeerAddress::First,
+            routes: NsRoutesConfig::default(),
+            backend: LinkBackend::Veth,
+            backends: HashMap::new(),
+            link_properties: LinkProperties::default(),
+            vlans: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct CaptureConfig {
+    // pcapng output; capturing is disabled when unset
+    pub path: Option<PathBuf>,
+    // bytes per file before rotating to `<path>.1`; 0 never rotates
+    pub max_size: u64,
+    pub max_files: usize,
+    // only capture these client MACs / message types (all when empty)
+    pub mac: Vec<String>,
+    #[serde(rename = "type")]
+    pub types: Vec<String>,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            max_size: 10 * 1024 * 1024,
+            max_files: 5,
+            mac: Vec::new(),
+            types: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct EventLogConfig {
+    // JSON lines output, "-" for stdout; disabled when unset
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct TracingConfig {
+    // OTLP/gRPC collector, e.g. "http://127.0.0.1:4317"; no export when unset
+    pub otlp_endpoint: Option<String>,
+    pub service_name: String,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            service_name: String::from("middle-sock"),
+        }
+    }
+}
+
+// packets that fail to decode as DHCP
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct MalformedConfig {
+    pub policy: MalformedPolicy,
+    // seconds between hex dumps of undecodable packets; 0 disables them
+    pub dump_interval: u64,
+}
+
+impl Default for MalformedConfig {
+    fn default() -> Self {
+        Self {
+            policy: MalformedPolicy::Strict,
+            dump_interval: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MalformedPolicy {
+    // drop them
+    Strict,
+    // forward them byte-for-byte if the BOOTP header looks sane
+    Passthrough,
+}
+
+// boot files handed to PXE clients; disabled when no file is set
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct PxeConfig {
+    // siaddr; also sent as option 66 unless `server-name` is set
+    pub next_server: Option<Ipv4Addr>,
+    pub server_name: Option<String>,
+    // x86 BIOS (arch 0), x86 UEFI (6, 7, 9), ARM64 UEFI (11), and clients already running iPXE
+    pub bios: Option<String>,
+    pub uefi: Option<String>,
+    pub arm64: Option<String>,
+    pub ipxe: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ProxyDhcpConfig {
+    // address PXE clients reach us at (option 54); the responder is disabled when unset
+    pub server_id: Option<Ipv4Addr>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct DnsConfig {
+    // resolv.conf whose servers and domains replace options 6, 15 and 119 in
+    // OFFER/ACK replies; disabled when unset
+    pub resolv_conf: Option<PathBuf>,
+}
+
+// options 1, 3, 121 and 249 in OFFER/ACK replies, from the route table entry of the
+// client's interface
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct RouteOptionsConfig {
+    pub enabled: bool,
+    // replace options the server sent instead of only filling in missing ones
+    pub overwrite: bool,
+}
+
+// the upstream server hands out `internal`; clients see `external`.
+// e.g. internal = "10.99.0.0/24", external = "192.168.1.0/24"
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct NatConfig {
+    pub internal: Option<Ipv4Net>,
+    pub external: Option<Ipv4Net>,
+}
@@ -0,0 +1,179 @@
+use std::{
+    fmt::Write as _,
+    fs, io,
+    os::unix::fs::{chown, FileTypeExt, PermissionsExt},
+    path::Path,
+    sync::Arc,
+};
+
+use log::{debug, info, warn};
+use nix::unistd::Group;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+
+use crate::{metrics::metrics, state::State};
+
+pub const DEFAULT_SOCKET: &str = "/run/middle-sock.sock";
+
+const HELP: &str = "\
+links              list namespaces and links created by setup_ns
+routes             show the parsed RouteInfo map
+child              show the child process status
+rules              show the active rules
+counters           dump metrics
+reload             re-read the config file
+restart            restart the child process
+enable <iface>     enable forwarding for an interface
+disable <iface>    disable forwarding for an interface
+";
+
+// one command per connection; the response is written back and the connection closed
+pub async fn serve<P: AsRef<Path>>(path: P, state: Arc<State>) -> io::Result<()> {
+    let path = path.as_ref();
+    remove_stale(path).await?;
+    let listener = UnixListener::bind(path)?;
+    let group = state.config.read().unwrap().ctl.group.clone();
+    let mode = match group {
+        Some(name) => {
+            let group = Group::from_name(&name)?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("no group {}", name))
+            })?;
+            chown(path, None, Some(group.gid.as_raw()))?;
+            0o660
+        }
+        None => 0o600,
+    };
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    info!("control socket listening on {}", path.display());
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, state).await {
+                warn!("(ctl) connection failed: {}", e);
+            }
+        });
+    }
+}
+
+// a socket left behind by an instance that is gone. anything else at the path is kept.
+async fn remove_stale(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(m) => m,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+    if UnixStream::connect(path).await.is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is in use by another instance", path.display()),
+        ));
+    }
+    debug!("(ctl) removing stale socket {}", path.display());
+    fs::remove_file(path)
+}
+
+async fn handle(stream: UnixStream, state: Arc<State>) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut line = String::new();
+    BufReader::new(reader).read_line(&mut line).await?;
+    debug!("(ctl) command: {}", line.trim());
+    // reload and restart wait on the network and the child
+    let response = tokio::task::spawn_blocking(move || execute(&state, line.trim()))
+        .await
+        .map_err(io::Error::other)?;
+    writer.write_all(response.as_bytes()).await?;
+    writer.shutdown().await
+}
+
+fn execute(state: &State, line: &str) -> String {
+    let mut out = String::new();
+    let args: Vec<_> = line.split_whitespace().collect();
+    match args.as_slice() {
+        ["links"] => {
+            for r in state.resources.lock().unwrap().iter() {
+                let _ = writeln!(out, "{}", r);
+            }
+        }
+        ["routes"] => {
+            for (k, v) in state.route_info.read().unwrap().iter() {
+                let _ = writeln!(out, "{} {}", k, v);
+            }
+        }
+        ["child"] => match state.child.lock().unwrap().as_mut() {
+            Some(executor) => {
+                let _ = writeln!(out, "{}", executor.status());
+            }
+            None => out.push_str("not started\n"),
+        },
+        ["rules"] => {
+            let _ = write!(out, "{}", state.rules());
+            let disabled = state.disabled.read().unwrap();
+            for iface in disabled.iter() {
+                let _ = writeln!(out, "disabled {}", iface);
+            }
+        }
+        ["counters"] => out = metrics().render(),
+        ["reload"] => match state.reload() {
+            Ok(changes) => {
+                for c in changes {
+                    let _ = writeln!(out, "{}", c);
+                }
+                out.push_str("reloaded\n");
+            }
+            Err(e) => {
+                let _ = writeln!(out, "error: {}", e);
+            }
+        },
+        ["restart"] => match state.child.lock().unwrap().as_mut() {
+            Some(executor) => match executor.restart() {
+                Ok(()) => {
+                    let _ = writeln!(out, "{}", executor.status());
+                }
+                Err(e) => {
+                    let _ = writeln!(out, "error: {}", e);
+                }
+            },
+            None => out.push_str("error: child not started\n"),
+        },
+        // one disabled before it left the route table can still be enabled
+        ["enable", iface] => {
+            let known = state.route_info.read().unwrap().contains_key(*iface);
+            if !state.disabled.write().unwrap().remove(*iface) && !known {
+                let _ = writeln!(out, "error: unknown interface {}", iface);
+                return out;
+            }
+            info!("forwarding enabled on {}", iface);
+            let _ = writeln!(out, "forwarding enabled on {}", iface);
+        }
+        ["disable", iface] => {
+            if !state.route_info.read().unwrap().contains_key(*iface) {
+                let _ = writeln!(out, "error: unknown interface {}", iface);
+                return out;
+            }
+            state.disabled.write().unwrap().insert(iface.to_string());
+            info!("forwarding disabled on {}", iface);
+            let _ = writeln!(out, "forwarding disabled on {}", iface);
+        }
+        _ => out.push_str(HELP),
+    }
+    out
+}
+
+pub async fn request<P: AsRef<Path>>(path: P, command: &str) -> io::Result<String> {
+    let mut stream = UnixStream::connect(path).await?;
+    stream
+        .write_all(format!("{}\n", command).as_bytes())
+        .await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    Ok(response)
+}
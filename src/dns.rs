@@ -0,0 +1,148 @@
+use std::{
+    fs, io,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    thread,
+};
+
+use dhcproto::{
+    v4::{DhcpOption, Message},
+    Name,
+};
+use log::{info, warn};
+use tokio::sync::Notify;
+
+use crate::{state::State, watch::watch_file};
+
+// the parts of resolv.conf that map to DHCP options
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Resolver {
+    pub servers: Vec<Ipv4Addr>,
+    pub domain: Option<String>,
+    pub search: Vec<String>,
+}
+
+impl Resolver {
+    // ref: resolv.conf(5). loopback servers (e.g. Docker's 127.0.0.11) are skipped since
+    // clients cannot reach them, and IPv6 ones do not fit in option 6.
+    pub fn parse(s: &str) -> Self {
+        let mut resolver = Self::default();
+        for line in s.lines() {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("nameserver") => {
+                    if let Some(Ok(ip)) = words.next().map(|w| w.parse::<Ipv4Addr>()) {
+                        if !ip.is_loopback() {
+                            resolver.servers.push(ip);
+                        }
+                    }
+                }
+                // `domain` and `search` override each other; the last one wins
+                Some("domain") => {
+                    resolver.domain = words.next().map(String::from);
+                    resolver.search.clear();
+                }
+                Some("search") => {
+                    resolver.search = words.map(String::from).collect();
+                    resolver.domain = None;
+                }
+                _ => {}
+            }
+        }
+        resolver
+    }
+
+    // option 15 falls back to the first search domain
+    fn domain(&self) -> Option<&str> {
+        self.domain
+            .as_deref()
+            .or_else(|| self.search.first().map(String::as_str))
+    }
+
+    // overwrites options 6, 15 and 119 with what the resolver has; options it has
+    // nothing for are left as the server sent them. a resolver without servers (e.g. only
+    // loopback ones) changes nothing. returns whether it did.
+    pub fn apply(&self, msg: &mut Message) -> bool {
+        if self.servers.is_empty() {
+            return false;
+        }
+        let opts = msg.opts_mut();
+        opts.insert(DhcpOption::DomainNameServer(self.servers.clone()));
+        if let Some(domain) = self.domain() {
+            opts.insert(DhcpOption::DomainName(domain.to_string()));
+        }
+        let search: Vec<_> = self
+            .search
+            .iter()
+            .filter_map(|d| Name::from_ascii(d).ok())
+            .collect();
+        if !search.is_empty() {
+            opts.insert(DhcpOption::DomainSearch(search));
+        }
+        true
+    }
+}
+
+#[derive(Debug)]
+pub struct Dns {
+    path: PathBuf,
+    resolver: RwLock<Arc<Resolver>>,
+}
+
+impl Dns {
+    pub fn new<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let dns = Self {
+            path: path.as_ref().to_path_buf(),
+            resolver: RwLock::default(),
+        };
+        dns.refresh()?;
+        Ok(dns)
+    }
+
+    pub fn resolver(&self) -> Arc<Resolver> {
+        Arc::clone(&self.resolver.read().unwrap())
+    }
+
+    pub fn refresh(&self) -> io::Result<()> {
+        let resolver = Resolver::parse(&fs::read_to_string(&self.path)?);
+        if resolver.servers.is_empty() {
+            warn!(
+                "(dns) no usable nameserver in {}; replies keep the server's DNS options",
+                self.path.display()
+            );
+        }
+        info!(
+            "resolver from {}: servers={:?} domain={:?} search={:?}",
+            self.path.display(),
+            resolver.servers,
+            resolver.domain,
+            resolver.search
+        );
+        *self.resolver.write().unwrap() = Arc::new(resolver);
+        Ok(())
+    }
+}
+
+// keeps the resolver in sync with the file; the last good contents are kept
+// while the file is missing or unreadable
+pub async fn watch(state: Arc<State>) {
+    let Some(dns) = &state.dns else {
+        return;
+    };
+    let notify = Arc::new(Notify::new());
+    let changed = Arc::clone(&notify);
+    let path = dns.path.clone();
+    // a plain thread, as the runtime would wait for a blocking task on shutdown
+    thread::spawn(move || {
+        if let Err(e) = watch_file(&path, &changed) {
+            warn!("(dns) stopped following {}: {}", path.display(), e);
+        }
+    });
+    loop {
+        notify.notified().await;
+        if let Err(e) = dns.refresh() {
+            warn!("(dns) could not read {}: {}", dns.path.display(), e);
+        }
+    }
+}
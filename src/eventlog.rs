@@ -0,0 +1,158 @@
+use std::{
+    fmt,
+    fs::OpenOptions,
+    io::{self, BufWriter, Write},
+    net::{Ipv4Addr, SocketAddr},
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use dhcproto::v4::{DhcpOption, OptionCode};
+use log::warn;
+use serde::Serialize;
+
+use crate::packet::{format_mac, peek_header, DHCPMessage};
+
+// one record per relayed (or dropped) message, written as a JSON line
+#[derive(Debug, Serialize)]
+pub struct Event<'a> {
+    pub timestamp: String,
+    pub direction: &'a str,
+    pub interface: Option<&'a str>,
+    pub src: SocketAddr,
+    pub dst: Option<SocketAddr>,
+    pub xid: String,
+    pub chaddr: String,
+    pub message_type: String,
+    pub requested_ip: Option<Ipv4Addr>,
+    pub assigned_ip: Option<Ipv4Addr>,
+    pub options: Vec<u8>,
+    pub rules: &'a [String],
+    pub action: &'a str,
+    pub reason: Option<&'a str>,
+}
+
+impl<'a> Event<'a> {
+    pub fn new(direction: &'a str, msg: &DHCPMessage, src: SocketAddr) -> Self {
+        let raw = msg.raw();
+        let requested_ip = match raw.opts().get(OptionCode::RequestedIpAddress) {
+            Some(DhcpOption::RequestedIpAddress(ip)) => Some(*ip),
+            _ => None,
+        };
+        let mut options: Vec<u8> = raw.opts().iter().map(|(code, _)| u8::from(*code)).collect();
+        options.sort_unstable();
+        Self {
+            timestamp: rfc3339(SystemTime::now()),
+            direction,
+            interface: None,
+            src,
+            dst: None,
+            xid: format!("{:#010x}", msg.xid()),
+            chaddr: format_mac(msg.chaddr()),
+            message_type: msg.msg_type_name(),
+            requested_ip,
+            assigned_ip: Some(raw.yiaddr()).filter(|ip| !ip.is_unspecified()),
+            options,
+            rules: &[],
+            action: "forwarded",
+            reason: None,
+        }
+    }
+
+    // a datagram that is not decoded; only the fixed header is read
+    pub fn from_header(
+        direction: &'a str,
+        message_type: &str,
+        data: &[u8],
+        src: SocketAddr,
+    ) -> Self {
+        let (xid, chaddr) = peek_header(data).unwrap_or_default();
+        Self {
+            timestamp: rfc3339(SystemTime::now()),
+            direction,
+            interface: None,
+            src,
+            dst: None,
+            xid: format!("{:#010x}", xid),
+            chaddr: format_mac(chaddr),
+            message_type: message_type.to_string(),
+            requested_ip: None,
+            assigned_ip: None,
+            options: Vec::new(),
+            rules: &[],
+            action: "forwarded",
+            reason: None,
+        }
+    }
+
+    pub fn dropped(self, reason: &'a str) -> Self {
+        Self {
+            action: "dropped",
+            reason: Some(reason),
+            ..self
+        }
+    }
+}
+
+pub struct EventLog {
+    out: Mutex<BufWriter<Box<dyn Write + Send>>>,
+}
+
+impl fmt::Debug for EventLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventLog").finish_non_exhaustive()
+    }
+}
+
+impl EventLog {
+    // "-" writes to stdout
+    pub fn new<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let out: Box<dyn Write + Send> = if path.as_ref() == Path::new("-") {
+            Box::new(io::stdout())
+        } else {
+            Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+        };
+        Ok(Self {
+            out: Mutex::new(BufWriter::new(out)),
+        })
+    }
+
+    pub fn write(&self, event: &Event) {
+        let mut out = self.out.lock().unwrap();
+        let res = serde_json::to_writer(&mut *out, event)
+            .map_err(io::Error::from)
+            .and_then(|_| out.write_all(b"\n"))
+            .and_then(|_| out.flush());
+        if let Err(e) = res {
+            warn!("(event log) could not write event: {}", e);
+        }
+    }
+}
+
+// UTC, millisecond precision
+// ref: http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn rfc3339(t: SystemTime) -> String {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = d.as_secs() as i64;
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        d.subsec_millis()
+    )
+}
@@ -0,0 +1,89 @@
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    panic::{self, AssertUnwindSafe},
+    process::exit,
+    sync::{Mutex, OnceLock},
+};
+
+use log::{error, info};
+use nix::unistd::{fork, ForkResult};
+
+use crate::plan::Step;
+
+// steps in another namespace fork into it, and a child forked while other threads (the
+// runtime's workers) hold a lock can get stuck on it. so the steps run in a helper forked
+// at startup, before there is any other thread, which forks for them in turn.
+static HELPER: OnceLock<Helper> = OnceLock::new();
+
+// our end of the socket pair: one JSON step per line, answered by one JSON result per line
+#[derive(Debug)]
+pub struct Helper(Mutex<BufReader<UnixStream>>);
+
+// forks the helper; call before starting any thread
+pub fn spawn() -> io::Result<()> {
+    let (ours, theirs) = UnixStream::pair()?;
+    match unsafe { fork() }? {
+        ForkResult::Child => {
+            drop(ours);
+            match serve(theirs) {
+                Ok(()) => exit(0),
+                Err(e) => {
+                    error!("(helper) stopped: {}", e);
+                    exit(1)
+                }
+            }
+        }
+        ForkResult::Parent { child } => {
+            drop(theirs);
+            info!("namespace helper started; pid: {}", child);
+            HELPER
+                .set(Helper(Mutex::new(BufReader::new(ours))))
+                .map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        "namespace helper already started",
+                    )
+                })
+        }
+    }
+}
+
+pub fn get() -> Option<&'static Helper> {
+    HELPER.get()
+}
+
+impl Helper {
+    // one step at a time; its error comes back as the message
+    pub fn run(&self, step: &Step) -> io::Result<()> {
+        let mut stream = self.0.lock().unwrap();
+        let mut request = serde_json::to_string(step)?;
+        request.push('\n');
+        stream.get_mut().write_all(request.as_bytes())?;
+        let mut reply = String::new();
+        if stream.read_line(&mut reply)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "namespace helper exited",
+            ));
+        }
+        let res: Result<(), String> = serde_json::from_str(&reply)?;
+        res.map_err(io::Error::other)
+    }
+}
+
+// runs the steps it is sent until middle-sock closes its end (or exits)
+fn serve(stream: UnixStream) -> io::Result<()> {
+    let mut replies = stream.try_clone()?;
+    for request in BufReader::new(stream).lines() {
+        let step: Step = serde_json::from_str(&request?)?;
+        let res = match panic::catch_unwind(AssertUnwindSafe(|| step.apply())) {
+            Ok(res) => res.map_err(|e| e.to_string()),
+            Err(_panic) => Err(format!("{}: panicked", step)),
+        };
+        let mut reply = serde_json::to_string(&res)?;
+        reply.push('\n');
+        replies.write_all(reply.as_bytes())?;
+    }
+    Ok(())
+}
@@ -1,12 +1,15 @@
 use std::{collections::HashMap, io, net::Ipv4Addr, path::Path};
 
-use log::info;
-use network::{
-    add_address, add_address_with_ns, add_ns, create_veth_pair, set_link_up, set_link_up_with_ns,
-    set_veth_to_ns,
-};
+use config::{LinkBackend, NetworkConfig, PeerAddress};
+use ipnet::Ipv4Net;
+use log::{info, warn};
+use metrics::{metrics, SETUP_STATUS};
+use network::{addresses, link_mtu, neighbours, netns_exists};
+use plan::{ChildKind, Host, MacAddr, Step};
 use process::ProcessExecutor;
-use route::{Route, RouteInfo, SEG_1, SEG_2, SEG_3, SEG_4};
+use route::{Route, RouteInfo};
+use state::Resource;
+use tracing::info_span;
 
 mod route;
 
@@ -26,57 +29,368 @@ pub fn setup_ns<
 >(
     link_name_new: T,
     link_name_host: T,
-    ns_name: T,
     ip: U,
     route_info: &RouteInfo,
-) -> io::Result<()> {
-    let prefix = mask_to_prefix(route_info.mask);
-    let prefix_rpos = 32 - prefix;
-    let ip_octets: [u8; 4] = ip.clone().into().octets();
-    let ip_octet_0 = u64::from(ip_octets[0]);
-    let ip_octet_1 = u64::from(ip_octets[1]);
-    let ip_octet_2 = u64::from(ip_octets[2]);
-    let ip_octet_3 = u64::from(ip_octets[3]);
-    let ip_to_u64 = (ip_octet_0 << 24) + (ip_octet_1 << 16) + (ip_octet_2 << 8) + ip_octet_3;
-    let ip_range_fixed: u64 = (ip_to_u64 & (0xFFFFFFFF << prefix_rpos)) + 1;
-    let peer_ip = Ipv4Addr::new(
-        ((ip_range_fixed & SEG_1) >> 24) as u8,
-        ((ip_range_fixed & SEG_2) >> 16) as u8,
-        ((ip_range_fixed & SEG_3) >> 8) as u8,
-        (ip_range_fixed & SEG_4) as u8,
-    );
-    add_ns(ns_name.clone())?;
-    create_veth_pair(link_name_new.clone(), link_name_host.clone())?;
-    set_veth_to_ns(link_name_host.clone(), ns_name.clone())?;
-    add_address(link_name_new.clone(), peer_ip, prefix)?;
-    add_address_with_ns(
-        link_name_host.clone(),
-        ip.clone().into(),
-        prefix,
-        ns_name.clone(),
+    network: &NetworkConfig,
+) -> io::Result<Vec<Resource>> {
+    let ns = network.netns.as_str();
+    let _span = info_span!("setup_ns", netns = ns).entered();
+    let link_name_host = link_name_host.into();
+    let mut host = Host {
+        // later interfaces join the namespace created for the first one
+        create_netns: needs_netns(network)?,
+        used: Vec::new(),
+        mtu: link_mtu(&link_name_host)?,
+    };
+    if matches!(network.peer, PeerAddress::Auto | PeerAddress::LinkLocal(_)) {
+        host.used.extend(addresses()?);
+        host.used.extend(neighbours()?);
+    }
+    let steps = plan_ns(
+        &link_name_new.into(),
+        &link_name_host,
+        ip.into(),
+        route_info,
+        network,
+        &host,
     )?;
-    set_link_up(link_name_new.clone())?;
-    set_link_up_with_ns(link_name_host.clone(), ns_name.clone())?;
+    let resources = run_steps(&steps)?;
     info!("setup_ns done!");
-    // add_route(ip.clone().into(), prefix, info.gateway, handle).await?;
-    Ok(())
+    Ok(resources)
+}
+
+// runs the steps in order. when one fails, the links created by the earlier ones are
+// deleted again (addresses and routes go with them; a namespace and rules stay).
+pub(crate) fn run_steps(steps: &[Step]) -> io::Result<Vec<Resource>> {
+    let mut resources = Vec::new();
+    for (i, step) in steps.iter().enumerate() {
+        let (kind, name) = step.label();
+        if let Err(e) = track(kind, &name, || step.run()) {
+            rollback(&steps[..i]);
+            return Err(e);
+        }
+        resources.extend(step.resource());
+    }
+    Ok(resources)
 }
 
-fn mask_to_prefix(mask: Ipv4Addr) -> u8 {
-    let mut prefix: u8 = 0;
-    for mut v in mask.octets() {
-        while v != 0 {
-            prefix += v & 1;
-            v >>= 1;
+fn rollback(done: &[Step]) {
+    for step in done.iter().rev() {
+        let link = match step {
+            Step::CreateVeth { link, .. }
+            | Step::CreateChild { link, .. }
+            | Step::CreateVlan { link, .. } => link,
+            _ => continue,
+        };
+        // a veth pair goes with its host side; a child may have been moved already
+        let netns = done.iter().find_map(|s| match s {
+            Step::MoveToNetns { link: moved, netns } if moved == link => Some(netns.clone()),
+            _ => None,
+        });
+        let del = Step::DelLink {
+            link: link.clone(),
+            netns,
+        };
+        match del.run() {
+            Ok(()) => info!("rolled back: {}", del),
+            Err(e) => warn!("could not roll back ({}): {}", del, e),
         }
     }
-    prefix
+}
+
+// whether the namespace has to be added first. one middle-sock does not own (a path, or
+// with `create-netns` off) must exist already, e.g. that of a container started elsewhere.
+pub(crate) fn needs_netns(network: &NetworkConfig) -> io::Result<bool> {
+    let ns = network.netns.as_str();
+    match (netns_exists(ns), network.owns_netns()) {
+        (true, _) => Ok(false),
+        (false, true) => Ok(true),
+        (false, false) => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("netns {} does not exist", ns),
+        )),
+    }
+}
+
+// the steps of `setup_ns`, without running them
+pub fn plan_ns(
+    link_name_new: &str,
+    link_name_host: &str,
+    ip: Ipv4Addr,
+    route_info: &RouteInfo,
+    network: &NetworkConfig,
+    host: &Host,
+) -> io::Result<Vec<Step>> {
+    // the connected subnet holding the server, or the preferred one when none does
+    let route_subnet = route_info
+        .subnet_for(ip)
+        .or_else(|| route_info.subnet())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no connected subnet"))?;
+    let prefix = route_subnet.prefix_len();
+    // the server's subnet, with the prefix of the host route it belongs to
+    let subnet = Ipv4Net::new(ip, prefix).map_err(io::Error::other)?.trunc();
+    let new = link_name_new.to_string();
+    let ns = network.netns.as_str();
+    let in_ns = Some(ns.to_string());
+    let mut steps = Vec::new();
+    if host.create_netns {
+        steps.push(Step::AddNetns {
+            name: ns.to_string(),
+        });
+    }
+    let backend = network.backend_for(link_name_host);
+    // the link the server address goes on. a bridged interface is in this namespace,
+    // so its name is not free for the namespace side
+    let ns_link = match backend {
+        LinkBackend::Veth => link_name_host.to_string(),
+        LinkBackend::Bridge(_) => format!("{}p", new),
+        LinkBackend::Macvlan | LinkBackend::Ipvlan => new.clone(),
+    };
+    let props = &network.link_properties;
+    let mtu = props.mtu.or_else(|| route_info.mtu()).or(host.mtu);
+    let alias = (!props.alias.is_empty()).then(|| format!("{}:{}", props.alias, link_name_host));
+    let set_link = |link: &str, mac: bool| {
+        let mac = (mac && props.stable_mac).then(|| MacAddr::stable(ns, link));
+        let unset = mtu.is_none() && mac.is_none() && props.txqueuelen.is_none();
+        if unset && alias.is_none() {
+            return None;
+        }
+        Some(Step::SetLink {
+            link: link.to_string(),
+            mtu,
+            mac,
+            txqueuelen: props.txqueuelen,
+            alias: alias.clone(),
+        })
+    };
+    let add_server_address = Step::AddAddress {
+        link: ns_link.clone(),
+        netns: in_ns.clone(),
+        address: ip,
+        prefix,
+    };
+    let ns_link_up = Step::LinkUp {
+        link: ns_link.clone(),
+        netns: in_ns.clone(),
+    };
+    let gateway = match backend {
+        LinkBackend::Veth => {
+            let (peer_ip, peer_prefix, ns_peer_ip) =
+                peer_address(network.peer, ip, subnet, route_info, &host.used)?;
+            info!("peer address: {}/{}", peer_ip, peer_prefix);
+            steps.push(Step::CreateVeth {
+                link: new.clone(),
+                peer: ns_link.clone(),
+            });
+            steps.extend(set_link(&new, true));
+            steps.extend(set_link(&ns_link, true));
+            steps.extend([
+                Step::MoveToNetns {
+                    link: ns_link.clone(),
+                    netns: ns.to_string(),
+                },
+                Step::AddAddress {
+                    link: new.clone(),
+                    netns: None,
+                    address: peer_ip,
+                    prefix: peer_prefix,
+                },
+                add_server_address,
+            ]);
+            if let Some(ns_peer_ip) = ns_peer_ip {
+                steps.push(Step::AddAddress {
+                    link: ns_link.clone(),
+                    netns: in_ns.clone(),
+                    address: ns_peer_ip,
+                    prefix: peer_prefix,
+                });
+            }
+            steps.extend([
+                Step::LinkUp {
+                    link: new,
+                    netns: None,
+                },
+                ns_link_up,
+            ]);
+            // the server address is not on the host side's subnet, so route it via the pair
+            if let Some(ns_peer_ip) = ns_peer_ip {
+                steps.push(Step::AddRoute {
+                    destination: ip,
+                    prefix: 32,
+                    gateway: ns_peer_ip,
+                    table: None,
+                    metric: None,
+                    netns: None,
+                });
+            }
+            Some(peer_ip)
+        }
+        LinkBackend::Bridge(bridge) => {
+            steps.push(Step::CreateVeth {
+                link: new.clone(),
+                peer: ns_link.clone(),
+            });
+            steps.extend(set_link(&new, true));
+            steps.extend(set_link(&ns_link, true));
+            steps.extend([
+                Step::MoveToNetns {
+                    link: ns_link.clone(),
+                    netns: ns.to_string(),
+                },
+                Step::SetMaster {
+                    link: new.clone(),
+                    master: bridge.clone(),
+                },
+                add_server_address,
+                Step::LinkUp {
+                    link: new,
+                    netns: None,
+                },
+                ns_link_up,
+            ]);
+            None
+        }
+        LinkBackend::Macvlan | LinkBackend::Ipvlan => {
+            let kind = match backend {
+                LinkBackend::Macvlan => ChildKind::Macvlan,
+                _ => ChildKind::Ipvlan,
+            };
+            steps.push(Step::CreateChild {
+                link: new.clone(),
+                parent: link_name_host.to_string(),
+                kind,
+            });
+            // an ipvlan child has the parent's MAC
+            steps.extend(set_link(&new, kind == ChildKind::Macvlan));
+            steps.extend([
+                Step::MoveToNetns {
+                    link: new,
+                    netns: ns.to_string(),
+                },
+                add_server_address,
+                ns_link_up,
+            ]);
+            None
+        }
+    };
+    // the way back to relayed clients: via the host side of a veth pair, or through the
+    // interface's own routers when the namespace sits on its segment
+    let routes = &network.routes;
+    let route = |destination: Ipv4Net, gateway: Ipv4Addr, metric: Option<u32>| Step::AddRoute {
+        destination: destination.network(),
+        prefix: destination.prefix_len(),
+        gateway,
+        table: routes.table,
+        metric,
+        netns: in_ns.clone(),
+    };
+    let default_gateway = gateway.or_else(|| route_info.gateway());
+    if routes.clients {
+        let subnets = route_info
+            .subnets
+            .iter()
+            .filter_map(|e| Some((e.destination, default_gateway?)));
+        let via = route_info
+            .routes
+            .iter()
+            .filter_map(|e| Some((e.destination, gateway.or(e.gateway)?)));
+        let clients = subnets
+            .chain(via)
+            .filter(|(d, _)| *d != subnet && !subnet.contains(d));
+        steps.extend(clients.map(|(d, g)| route(d, g, None)));
+    }
+    if let (true, Some(default_gateway)) = (routes.default, default_gateway) {
+        // the interface the host prefers is preferred here as well
+        let metric = route_info.default.map(|d| d.metric.max(0) as u32);
+        steps.push(route(Ipv4Net::default(), default_gateway, metric));
+    }
+    // with the namespace, or with each link for one that is not ours (a rule already
+    // there is left as is)
+    let add_rule = host.create_netns || !network.owns_netns();
+    if let (Some(table), true) = (routes.table, add_rule) {
+        steps.push(Step::AddRule {
+            from: ip,
+            table,
+            netns: ns.to_string(),
+        });
+    }
+    Ok(steps)
+}
+
+// (host side address, its prefix, address for the namespace side besides the server's)
+fn peer_address(
+    peer: PeerAddress,
+    ip: Ipv4Addr,
+    subnet: Ipv4Net,
+    route_info: &RouteInfo,
+    used: &[Ipv4Addr],
+) -> io::Result<(Ipv4Addr, u8, Option<Ipv4Addr>)> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    let prefix = subnet.prefix_len();
+    match peer {
+        PeerAddress::First => {
+            let first = Ipv4Addr::from(u32::from(subnet.network()) + 1);
+            Ok((first, prefix, None))
+        }
+        PeerAddress::Explicit(peer_ip) => {
+            let reserved =
+                prefix < 31 && (peer_ip == subnet.network() || peer_ip == subnet.broadcast());
+            if !subnet.contains(&peer_ip) || reserved || peer_ip == ip {
+                return Err(invalid(format!(
+                    "peer address {} is not a free host of {}",
+                    peer_ip, subnet
+                )));
+            }
+            Ok((peer_ip, prefix, None))
+        }
+        PeerAddress::Auto => {
+            let mut used = used.to_vec();
+            used.push(ip);
+            used.extend(route_info.gateway());
+            // from the top of the range, as gateways and servers tend to sit at the bottom
+            let peer_ip = subnet
+                .hosts()
+                .rev()
+                .find(|h| !used.contains(h))
+                .ok_or_else(|| invalid(format!("no free address in {}", subnet)))?;
+            Ok((peer_ip, prefix, None))
+        }
+        PeerAddress::LinkLocal(len) => {
+            // 169.254.0.0/24 and 169.254.255.0/24 are reserved
+            // ref: https://www.rfc-editor.org/rfc/rfc3927#section-2.1
+            let size = 1u32 << (32 - len);
+            let start = u32::from(Ipv4Addr::new(169, 254, 1, 0));
+            let end = u32::from(Ipv4Addr::new(169, 254, 255, 0));
+            (start..end)
+                .step_by(size as usize)
+                .map(|base| match len {
+                    31 => (Ipv4Addr::from(base), Ipv4Addr::from(base + 1)),
+                    _ => (Ipv4Addr::from(base + 1), Ipv4Addr::from(base + 2)),
+                })
+                .find(|(a, b)| !used.contains(a) && !used.contains(b))
+                .map(|(a, b)| (a, len, Some(b)))
+                .ok_or_else(|| invalid(String::from("no free link-local pair")))
+        }
+    }
+}
+
+// runs a setup step in its own span and records the outcome as `middle_sock_setup_status`
+pub(crate) fn track<T, F: FnOnce() -> io::Result<T>>(
+    kind: &str,
+    name: &str,
+    step: F,
+) -> io::Result<T> {
+    let _span = info_span!("netlink", step = kind, name).entered();
+    let res = step();
+    let status = if res.is_ok() { 1.0 } else { 0.0 };
+    metrics().set(SETUP_STATUS, &[("kind", kind), ("name", name)], status);
+    res
 }
 
 mod packet;
 mod process;
 
-pub fn run_process<T: Into<String> + Clone>(cmd: T, netns_name: T) -> io::Result<()> {
+pub fn run_process<T: Into<String> + Clone>(cmd: T, netns_name: T) -> io::Result<ProcessExecutor> {
     let mut executor = ProcessExecutor::new(cmd);
 
     info!("run_process");
@@ -85,7 +399,23 @@ pub fn run_process<T: Into<String> + Clone>(cmd: T, netns_name: T) -> io::Result
         panic!("panic on executor");
     }
 
-    Ok(())
+    Ok(executor)
 }
 
+pub mod acl;
+pub mod capture;
+pub mod config;
+pub mod ctl;
+pub mod dns;
+pub mod eventlog;
+pub mod helper;
+pub mod metrics;
+pub mod nat;
+pub mod plan;
+pub mod proxy;
+pub mod pxe;
 pub mod socket;
+pub mod state;
+pub mod telemetry;
+pub mod vlan;
+pub mod watch;
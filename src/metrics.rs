@@ -0,0 +1,287 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io,
+    net::SocketAddr,
+    sync::{Mutex, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, info, warn};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+pub const PACKETS_RECEIVED: &str = "middle_sock_packets_received_total";
+pub const PACKETS_FORWARDED: &str = "middle_sock_packets_forwarded_total";
+pub const PACKETS_DROPPED: &str = "middle_sock_packets_dropped_total";
+pub const DECODE_FAILURES: &str = "middle_sock_decode_failures_total";
+pub const UPSTREAM_SEND_ERRORS: &str = "middle_sock_upstream_send_errors_total";
+pub const CHILD_ADMIN_RESTARTS: &str = "middle_sock_child_admin_restarts_total";
+pub const CHANNEL_DEPTH: &str = "middle_sock_channel_depth";
+pub const SETUP_STATUS: &str = "middle_sock_setup_status";
+pub const LAST_PACKET: &str = "middle_sock_last_packet_timestamp_seconds";
+pub const REPLY_LATENCY: &str = "middle_sock_reply_latency_seconds";
+pub const PASSTHROUGH_FORWARDED: &str = "middle_sock_passthrough_forwarded_total";
+pub const PROXY_REPLIES: &str = "middle_sock_proxy_dhcp_replies_total";
+pub const CAPTURE_ERRORS: &str = "middle_sock_capture_errors_total";
+
+// (name, type, help)
+const DESCRIPTIONS: &[(&str, &str, &str)] = &[
+    (PACKETS_RECEIVED, "counter", "DHCP packets received"),
+    (PACKETS_FORWARDED, "counter", "DHCP packets forwarded"),
+    (PACKETS_DROPPED, "counter", "DHCP packets dropped"),
+    (
+        DECODE_FAILURES,
+        "counter",
+        "packets that could not be decoded",
+    ),
+    (
+        UPSTREAM_SEND_ERRORS,
+        "counter",
+        "errors sending to an upstream server",
+    ),
+    (
+        CHILD_ADMIN_RESTARTS,
+        "counter",
+        "restarts of the child process by `middle-sock ctl restart`",
+    ),
+    (
+        CHANNEL_DEPTH,
+        "gauge",
+        "messages waiting in the sender channel",
+    ),
+    (
+        SETUP_STATUS,
+        "gauge",
+        "1 if the namespace/link setup step succeeded",
+    ),
+    (
+        LAST_PACKET,
+        "gauge",
+        "unix time of the last received DHCP packet",
+    ),
+    (
+        REPLY_LATENCY,
+        "histogram",
+        "time from forwarding a request to its reply",
+    ),
+    (
+        PASSTHROUGH_FORWARDED,
+        "counter",
+        "undecodable packets forwarded byte-for-byte",
+    ),
+    (PROXY_REPLIES, "counter", "proxyDHCP offers and acks sent"),
+    (
+        CAPTURE_ERRORS,
+        "counter",
+        "errors rotating or writing the packet capture",
+    ),
+];
+
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+type Series = (&'static str, String);
+
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    counters: Mutex<BTreeMap<Series, u64>>,
+    gauges: Mutex<BTreeMap<Series, f64>>,
+    histograms: Mutex<BTreeMap<Series, Histogram>>,
+}
+
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::default)
+}
+
+impl Metrics {
+    pub fn inc(&self, name: &'static str, labels: &[(&str, &str)]) {
+        let mut counters = self.counters.lock().unwrap();
+        *counters.entry((name, render_labels(labels))).or_default() += 1;
+    }
+
+    pub fn set(&self, name: &'static str, labels: &[(&str, &str)], v: f64) {
+        let mut gauges = self.gauges.lock().unwrap();
+        gauges.insert((name, render_labels(labels)), v);
+    }
+
+    pub fn add(&self, name: &'static str, labels: &[(&str, &str)], delta: f64) {
+        let mut gauges = self.gauges.lock().unwrap();
+        *gauges.entry((name, render_labels(labels))).or_default() += delta;
+    }
+
+    pub fn observe(&self, name: &'static str, labels: &[(&str, &str)], d: Duration) {
+        let v = d.as_secs_f64();
+        let mut histograms = self.histograms.lock().unwrap();
+        let h = histograms
+            .entry((name, render_labels(labels)))
+            .or_insert_with(|| Histogram {
+                buckets: vec![0; LATENCY_BUCKETS.len()],
+                ..Default::default()
+            });
+        for (i, le) in LATENCY_BUCKETS.iter().enumerate() {
+            if v <= *le {
+                h.buckets[i] += 1;
+            }
+        }
+        h.sum += v;
+        h.count += 1;
+    }
+
+    pub fn touch(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.set(LAST_PACKET, &[], now.as_secs_f64());
+    }
+
+    // Prometheus text exposition format
+    // ref: https://prometheus.io/docs/instrumenting/exposition_formats/
+    pub fn render(&self) -> String {
+        let counters = self.counters.lock().unwrap().clone();
+        let gauges = self.gauges.lock().unwrap().clone();
+        let histograms = self.histograms.lock().unwrap().clone();
+        let mut out = String::new();
+        for (name, kind, help) in DESCRIPTIONS {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for ((n, labels), v) in counters.iter().filter(|((n, _), _)| n == name) {
+                let _ = writeln!(out, "{}{} {}", n, braces(labels), v);
+            }
+            for ((n, labels), v) in gauges.iter().filter(|((n, _), _)| n == name) {
+                let _ = writeln!(out, "{}{} {}", n, braces(labels), v);
+            }
+            for ((n, labels), h) in histograms.iter().filter(|((n, _), _)| n == name) {
+                for (le, count) in LATENCY_BUCKETS.iter().zip(h.buckets.iter()) {
+                    let le = format!("le=\"{}\"", le);
+                    let _ = writeln!(out, "{}_bucket{} {}", n, join(labels, &le), count);
+                }
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{} {}",
+                    n,
+                    join(labels, "le=\"+Inf\""),
+                    h.count
+                );
+                let _ = writeln!(out, "{}_sum{} {}", n, braces(labels), h.sum);
+                let _ = writeln!(out, "{}_count{} {}", n, braces(labels), h.count);
+            }
+        }
+        out
+    }
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn braces(labels: &str) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels)
+    }
+}
+
+fn join(labels: &str, extra: &str) -> String {
+    if labels.is_empty() {
+        format!("{{{}}}", extra)
+    } else {
+        format!("{{{},{}}}", labels, extra)
+    }
+}
+
+// requests are read up to the end of their headers, or this many bytes
+const MAX_REQUEST: usize = 8192;
+// how long the listener waits before accepting again after a failure (e.g. EMFILE)
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+pub async fn serve(addr: SocketAddr) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("serving metrics on http://{}/metrics", addr);
+    loop {
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("(metrics) accept failed: {}", e);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            // the request may arrive in several segments; only its head is needed
+            let mut buf = Vec::new();
+            let mut chunk = [0; 1024];
+            while !buf.windows(4).any(|w| w == b"\r\n\r\n") && buf.len() < MAX_REQUEST {
+                match stream.read(&mut chunk).await {
+                    Ok(0) => break,
+                    Ok(len) => buf.extend_from_slice(&chunk[..len]),
+                    Err(e) => {
+                        debug!("(metrics) read from {} failed: {}", peer, e);
+                        return;
+                    }
+                }
+            }
+            let request = String::from_utf8_lossy(&buf);
+            let response = if request.starts_with("GET /metrics ") {
+                let body = metrics().render();
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            } else {
+                String::from(
+                    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                )
+            };
+            if let Err(e) = stream.write_all(response.as_bytes()).await {
+                warn!("(metrics) write to {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpStream;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn serve_reads_requests_split_across_segments() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|l| l.local_addr())
+            .unwrap();
+        tokio::spawn(serve(addr));
+        let mut stream = loop {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        stream.write_all(b"GET /metr").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stream
+            .write_all(b"ics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains(PACKETS_RECEIVED));
+    }
+}
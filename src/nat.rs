@@ -0,0 +1,220 @@
+use std::{fmt, io, net::Ipv4Addr};
+
+use dhcproto::v4::{DhcpOption, Message, UnknownOption};
+use ipnet::Ipv4Net;
+
+use crate::{
+    config::NatConfig,
+    route::{classless_routes, parse_classless_routes, MS_CLASSLESS_STATIC_ROUTE},
+};
+
+// 1:1 mapping between the range the upstream server hands out (internal) and the
+// client subnet (external); host bits are kept, so both must have the same prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Nat {
+    internal: Ipv4Net,
+    external: Ipv4Net,
+}
+
+impl Nat {
+    // None when not configured
+    pub fn new(config: &NatConfig) -> io::Result<Option<Self>> {
+        let (internal, external) = match (config.internal, config.external) {
+            (Some(i), Some(e)) => (i.trunc(), e.trunc()),
+            (None, None) => return Ok(None),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "nat needs both `internal` and `external`",
+                ))
+            }
+        };
+        if internal.prefix_len() != external.prefix_len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("nat: {} and {} differ in size", internal, external),
+            ));
+        }
+        Ok(Some(Self { internal, external }))
+    }
+
+    // requests on their way to the server
+    pub fn to_internal(&self, msg: &mut Message) {
+        translate(msg, self.external, self.internal);
+    }
+
+    // replies on their way to the client
+    pub fn to_external(&self, msg: &mut Message) {
+        translate(msg, self.internal, self.external);
+    }
+}
+
+impl fmt::Display for Nat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "nat internal={} external={}",
+            self.internal, self.external
+        )
+    }
+}
+
+// rewrites every address in `from` to the same host in `to`; addresses outside
+// `from` (e.g. a DNS server elsewhere) are left alone
+fn translate(msg: &mut Message, from: Ipv4Net, to: Ipv4Net) {
+    let map = |ip: Ipv4Addr| {
+        if !from.contains(&ip) {
+            return ip;
+        }
+        let host = u32::from(ip) & u32::from(from.hostmask());
+        Ipv4Addr::from(u32::from(to.network()) | host)
+    };
+    let map_net = |net: Ipv4Net| Ipv4Net::new(map(net.network()), net.prefix_len()).unwrap_or(net);
+    let (ciaddr, yiaddr, siaddr) = (msg.ciaddr(), msg.yiaddr(), msg.siaddr());
+    // the server picks the pool by giaddr
+    let giaddr = msg.giaddr();
+    msg.set_ciaddr(map(ciaddr))
+        .set_yiaddr(map(yiaddr))
+        .set_siaddr(map(siaddr))
+        .set_giaddr(map(giaddr));
+    for (_, opt) in msg.opts_mut().iter_mut() {
+        match opt {
+            DhcpOption::ServerIdentifier(ip)
+            | DhcpOption::RequestedIpAddress(ip)
+            | DhcpOption::BroadcastAddr(ip) => *ip = map(*ip),
+            DhcpOption::Router(ips)
+            | DhcpOption::DomainNameServer(ips)
+            | DhcpOption::NtpServers(ips)
+            | DhcpOption::NetBiosNameServers(ips) => {
+                for ip in ips.iter_mut() {
+                    *ip = map(*ip);
+                }
+            }
+            DhcpOption::StaticRoutingTable(routes) => {
+                for (dst, router) in routes.iter_mut() {
+                    *dst = map(*dst);
+                    *router = map(*router);
+                }
+            }
+            DhcpOption::ClasslessStaticRoute(routes) => {
+                for (dst, router) in routes.iter_mut() {
+                    *dst = map_net(*dst);
+                    *router = map(*router);
+                }
+            }
+            DhcpOption::Unknown(opt) if u8::from(opt.code()) == MS_CLASSLESS_STATIC_ROUTE => {
+                if let Some(mut routes) = parse_classless_routes(opt.data()) {
+                    for (dst, router) in routes.iter_mut() {
+                        *dst = map_net(*dst);
+                        *router = map(*router);
+                    }
+                    *opt = UnknownOption::new(opt.code(), classless_routes(&routes));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dhcproto::v4::OptionCode;
+
+    use super::*;
+
+    fn nat(internal: &str, external: &str) -> io::Result<Option<Nat>> {
+        Nat::new(&NatConfig {
+            internal: Some(internal.parse().unwrap()),
+            external: Some(external.parse().unwrap()),
+        })
+    }
+
+    fn ip(s: &str) -> Ipv4Addr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn new_checks_the_config() {
+        assert_eq!(Nat::new(&NatConfig::default()).unwrap(), None);
+        let half = NatConfig {
+            internal: Some("10.99.0.0/24".parse().unwrap()),
+            external: None,
+        };
+        assert!(Nat::new(&half).is_err());
+        assert!(nat("10.99.0.0/24", "192.168.0.0/16").is_err());
+        // host bits in the config are dropped
+        assert_eq!(
+            nat("10.99.0.7/24", "192.168.1.0/24").unwrap(),
+            nat("10.99.0.0/24", "192.168.1.0/24").unwrap()
+        );
+    }
+
+    #[test]
+    fn translates_both_ways() {
+        let nat = nat("10.99.0.0/24", "192.168.1.0/24").unwrap().unwrap();
+        let mut msg = Message::default();
+        msg.set_yiaddr(ip("10.99.0.50"))
+            .set_siaddr(ip("10.99.0.1"))
+            .set_giaddr(ip("192.168.1.1"));
+        let opts = msg.opts_mut();
+        opts.insert(DhcpOption::ServerIdentifier(ip("10.99.0.1")));
+        opts.insert(DhcpOption::Router(vec![ip("10.99.0.1"), ip("10.0.0.1")]));
+        opts.insert(DhcpOption::DomainNameServer(vec![ip("8.8.8.8")]));
+        let routes = [
+            ("10.99.0.128/25".parse().unwrap(), ip("10.99.0.2")),
+            ("172.16.0.0/12".parse().unwrap(), ip("10.99.0.1")),
+        ];
+        opts.insert(DhcpOption::ClasslessStaticRoute(routes.to_vec()));
+        opts.insert(DhcpOption::Unknown(UnknownOption::new(
+            OptionCode::from(MS_CLASSLESS_STATIC_ROUTE),
+            classless_routes(&routes),
+        )));
+        let original = msg.clone();
+
+        nat.to_external(&mut msg);
+        assert_eq!(msg.yiaddr(), ip("192.168.1.50"));
+        assert_eq!(msg.siaddr(), ip("192.168.1.1"));
+        assert_eq!(msg.giaddr(), ip("192.168.1.1"));
+        let opts = msg.opts();
+        assert_eq!(
+            opts.get(OptionCode::ServerIdentifier),
+            Some(&DhcpOption::ServerIdentifier(ip("192.168.1.1")))
+        );
+        assert_eq!(
+            opts.get(OptionCode::Router),
+            Some(&DhcpOption::Router(vec![ip("192.168.1.1"), ip("10.0.0.1")]))
+        );
+        assert_eq!(
+            opts.get(OptionCode::DomainNameServer),
+            Some(&DhcpOption::DomainNameServer(vec![ip("8.8.8.8")]))
+        );
+        let routes = [
+            ("192.168.1.128/25".parse().unwrap(), ip("192.168.1.2")),
+            ("172.16.0.0/12".parse().unwrap(), ip("192.168.1.1")),
+        ];
+        assert_eq!(
+            opts.get(OptionCode::ClasslessStaticRoute),
+            Some(&DhcpOption::ClasslessStaticRoute(routes.to_vec()))
+        );
+        let Some(DhcpOption::Unknown(ms)) = opts.get(OptionCode::from(MS_CLASSLESS_STATIC_ROUTE))
+        else {
+            panic!("option 249 missing");
+        };
+        assert_eq!(ms.data(), classless_routes(&routes));
+
+        nat.to_internal(&mut msg);
+        assert_eq!(msg.yiaddr(), original.yiaddr());
+        assert_eq!(msg.opts(), original.opts());
+    }
+
+    #[test]
+    fn translates_giaddr() {
+        let nat = nat("10.99.0.0/24", "192.168.1.0/24").unwrap().unwrap();
+        let mut msg = Message::default();
+        msg.set_giaddr(ip("192.168.1.1"));
+        nat.to_internal(&mut msg);
+        assert_eq!(msg.giaddr(), ip("10.99.0.1"));
+        nat.to_external(&mut msg);
+        assert_eq!(msg.giaddr(), ip("192.168.1.1"));
+    }
+}
@@ -3,42 +3,241 @@ use std::{
     io,
     net::{IpAddr, Ipv4Addr},
     os::unix::prelude::AsRawFd,
+    path::{Path, PathBuf},
     process::exit,
 };
 
 use futures::TryStreamExt;
 use log::{debug, info};
+use netlink_packet_route::{
+    address::AddressAttribute,
+    link::{InfoData, InfoIpVlan, InfoKind, LinkAttribute, LinkInfo},
+    neighbour::{NeighbourAddress, NeighbourAttribute},
+    route::RouteAttribute,
+    rule::{RuleAction, RuleAttribute},
+};
 use nix::{
+    net::if_::if_nametoindex,
     sched::{setns, CloneFlags},
-    sys::wait::waitpid,
+    sys::wait::{waitpid, WaitStatus},
     unistd::{fork, ForkResult},
 };
-use rtnetlink::{new_connection, Error, Handle, NetworkNamespace, NETNS_PATH};
+use rtnetlink::{new_connection, Error, Handle, IpVersion, NetworkNamespace, NETNS_PATH};
 
 pub async fn _add_route<T: Into<Ipv4Addr>>(
     dest: T,
     prefix: u8,
     gateway: T,
+    table: Option<u32>,
+    metric: Option<u32>,
     handle: &Handle,
 ) -> Result<(), Error> {
     let route = handle.route();
-    route
+    let mut add = route
         .add()
         .v4()
         .destination_prefix(dest.into(), prefix)
         .gateway(gateway.into())
-        .execute()
-        .await?;
+        .replace();
+    if let Some(table) = table {
+        add = add.table_id(table);
+    }
+    if let Some(metric) = metric {
+        add.message_mut()
+            .attributes
+            .push(RouteAttribute::Priority(metric));
+    }
+    add.execute().await?;
     Ok(())
 }
 
+pub fn add_route<T: Into<Ipv4Addr>>(dest: T, prefix: u8, gateway: T) -> io::Result<()> {
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let (connection, handle, _) = new_connection()?;
+        tokio::spawn(connection);
+        _add_route(dest, prefix, gateway, None, None, &handle)
+            .await
+            .map_err(io::Error::other)
+    })
+}
+
+pub fn add_route_with_ns<T: Into<Ipv4Addr> + std::panic::UnwindSafe>(
+    dest: T,
+    prefix: u8,
+    gateway: T,
+    table: Option<u32>,
+    metric: Option<u32>,
+    ns_name: &str,
+) -> io::Result<()> {
+    in_ns(ns_name, || {
+        let rt = tokio::runtime::Runtime::new()?;
+        rt.block_on(async {
+            let (connection, handle, _) = new_connection()?;
+            tokio::spawn(connection);
+            _add_route(dest, prefix, gateway, table, metric, &handle)
+                .await
+                .map_err(io::Error::other)
+        })
+    })
+}
+
+// `from <from> lookup <table>`; a rule already there (e.g. left by an earlier run in a
+// namespace middle-sock did not add) is left as is
+pub fn add_rule_with_ns(from: Ipv4Addr, table: u32, ns_name: &str) -> io::Result<()> {
+    in_ns(ns_name, || {
+        let rt = tokio::runtime::Runtime::new()?;
+        rt.block_on(async {
+            let (connection, handle, _) = new_connection()?;
+            tokio::spawn(connection);
+            let mut rules = handle.rule().get(IpVersion::V4).execute();
+            while let Some(rule) = rules.try_next().await.map_err(io::Error::other)? {
+                let attrs = &rule.attributes;
+                if rule.header.src_len == 32
+                    && attrs.contains(&RuleAttribute::Source(IpAddr::V4(from)))
+                    && attrs.contains(&RuleAttribute::Table(table))
+                {
+                    return Ok(());
+                }
+            }
+            handle
+                .rule()
+                .add()
+                .v4()
+                .source_prefix(from, 32)
+                .table_id(table)
+                .action(RuleAction::ToTable)
+                .execute()
+                .await
+                .map_err(io::Error::other)
+        })
+    })
+}
+
+// runs `f` in a child process inside the namespace; fails unless the child exits with 0
+fn in_ns<F: FnOnce() -> io::Result<()> + std::panic::UnwindSafe>(
+    ns_name: &str,
+    f: F,
+) -> io::Result<()> {
+    let ns_path = netns_path(ns_name);
+    match unsafe { fork() } {
+        Ok(ForkResult::Parent { child, .. }) => match waitpid(child, None)? {
+            WaitStatus::Exited(_, 0) => Ok(()),
+            status => Err(io::Error::other(format!(
+                "netns {}: child failed: {:?}",
+                ns_name, status
+            ))),
+        },
+        Ok(ForkResult::Child) => {
+            let res = std::panic::catch_unwind(|| -> io::Result<()> {
+                let ns = File::open(ns_path)?;
+                setns(ns, CloneFlags::CLONE_NEWNET)?;
+                f()
+            });
+            match res {
+                Err(_panic) => {
+                    log::error!("child process crashed");
+                    std::process::abort()
+                }
+                Ok(Err(fail)) => {
+                    log::error!("child process failed: {}", fail);
+                    exit(1)
+                }
+                Ok(Ok(())) => exit(0),
+            }
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// IPv4 addresses on any link of the current namespace
+pub fn addresses() -> io::Result<Vec<Ipv4Addr>> {
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let (connection, handle, _) = new_connection()?;
+        tokio::spawn(connection);
+        let mut addrs = handle.address().get().execute();
+        let mut out = Vec::new();
+        while let Some(msg) = addrs.try_next().await.map_err(io::Error::other)? {
+            for attr in msg.attributes {
+                if let AddressAttribute::Address(IpAddr::V4(ip)) = attr {
+                    out.push(ip);
+                }
+            }
+        }
+        Ok(out)
+    })
+}
+
+// IPv4 neighbours (ARP entries) of the current namespace
+pub fn neighbours() -> io::Result<Vec<Ipv4Addr>> {
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let (connection, handle, _) = new_connection()?;
+        tokio::spawn(connection);
+        let mut neighbours = handle.neighbours().get().execute();
+        let mut out = Vec::new();
+        while let Some(msg) = neighbours.try_next().await.map_err(io::Error::other)? {
+            for attr in msg.attributes {
+                if let NeighbourAttribute::Destination(NeighbourAddress::Inet(ip)) = attr {
+                    out.push(ip);
+                }
+            }
+        }
+        Ok(out)
+    })
+}
+
+// IPv4 addresses on one link of the current namespace; none when there is no such link
+pub fn link_addresses(link_name: &str) -> io::Result<Vec<Ipv4Addr>> {
+    if !link_exists(link_name) {
+        return Ok(Vec::new());
+    }
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let (connection, handle, _) = new_connection()?;
+        tokio::spawn(connection);
+        let index = link_index(&handle, link_name).await?;
+        let mut addrs = handle
+            .address()
+            .get()
+            .set_link_index_filter(index)
+            .execute();
+        let mut out = Vec::new();
+        while let Some(msg) = addrs.try_next().await.map_err(io::Error::other)? {
+            for attr in msg.attributes {
+                if let AddressAttribute::Address(IpAddr::V4(ip)) = attr {
+                    out.push(ip);
+                }
+            }
+        }
+        Ok(out)
+    })
+}
+
+pub fn link_exists(name: &str) -> bool {
+    if_nametoindex(name).is_ok()
+}
+
+// a name under /run/netns, or the path of a namespace file as it is
+pub fn netns_path(ns_name: &str) -> PathBuf {
+    match ns_name.starts_with('/') {
+        true => PathBuf::from(ns_name),
+        false => Path::new(NETNS_PATH).join(ns_name),
+    }
+}
+
+pub fn netns_exists(name: &str) -> bool {
+    netns_path(name).exists()
+}
+
 pub fn add_ns<T: Into<String>>(name: T) -> io::Result<()> {
     let rt = tokio::runtime::Runtime::new()?;
     rt.block_on(async {
         let (connection, _, _) = new_connection()?;
         tokio::spawn(connection);
         if let Err(e) = NetworkNamespace::add(name.into()).await {
-            Err(io::Error::new(io::ErrorKind::Other, e))
+            Err(io::Error::other(e))
         } else {
             Ok::<(), io::Error>(())
         }
@@ -46,6 +245,25 @@ pub fn add_ns<T: Into<String>>(name: T) -> io::Result<()> {
     Ok(())
 }
 
+// deleting one end of a veth pair removes the other end (and routes over it) as well
+pub fn del_link<T: Into<String>>(link_name: T) -> io::Result<()> {
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let (connection, handle, _) = new_connection()?;
+        tokio::spawn(connection);
+        let mut links = handle.link().get().match_name(link_name.into()).execute();
+        if let Ok(Some(link)) = links.try_next().await {
+            if let Err(e) = handle.link().del(link.header.index).execute().await {
+                return Err(io::Error::other(e));
+            }
+        } else {
+            info!("skipped");
+        }
+        Ok::<(), io::Error>(())
+    })?;
+    Ok(())
+}
+
 pub fn create_veth_pair<T: Into<String> + Clone>(link_name_1: T, link_name_2: T) -> io::Result<()> {
     let rt = tokio::runtime::Runtime::new()?;
     rt.block_on(async {
@@ -58,7 +276,7 @@ pub fn create_veth_pair<T: Into<String> + Clone>(link_name_1: T, link_name_2: T)
             .execute()
             .await
         {
-            Err(io::Error::new(io::ErrorKind::Other, e))
+            Err(io::Error::other(e))
         } else {
             Ok::<(), io::Error>(())
         }
@@ -66,6 +284,166 @@ pub fn create_veth_pair<T: Into<String> + Clone>(link_name_1: T, link_name_2: T)
     Ok(())
 }
 
+// macvlan modes; bridge lets children on the same parent reach each other
+// ref: https://github.com/torvalds/linux/blob/master/include/uapi/linux/if_link.h
+const MACVLAN_MODE_BRIDGE: u32 = 4;
+const IPVLAN_MODE_L2: u16 = 0;
+
+pub fn create_macvlan(link_name: &str, parent: &str) -> io::Result<()> {
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let (connection, handle, _) = new_connection()?;
+        tokio::spawn(connection);
+        let index = link_index(&handle, parent).await?;
+        handle
+            .link()
+            .add()
+            .macvlan(link_name.to_string(), index, MACVLAN_MODE_BRIDGE)
+            .execute()
+            .await
+            .map_err(io::Error::other)
+    })
+}
+
+// rtnetlink has no builder for ipvlan, so the message is put together here
+pub fn create_ipvlan(link_name: &str, parent: &str) -> io::Result<()> {
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let (connection, handle, _) = new_connection()?;
+        tokio::spawn(connection);
+        let index = link_index(&handle, parent).await?;
+        let mut add = handle.link().add();
+        add.message_mut().attributes.extend([
+            LinkAttribute::IfName(link_name.to_string()),
+            LinkAttribute::Link(index),
+            LinkAttribute::LinkInfo(vec![
+                LinkInfo::Kind(InfoKind::IpVlan),
+                LinkInfo::Data(InfoData::IpVlan(vec![InfoIpVlan::Mode(IPVLAN_MODE_L2)])),
+            ]),
+        ]);
+        add.execute().await.map_err(io::Error::other)
+    })
+}
+
+// an 802.1Q sub-interface of `parent`
+pub fn create_vlan(link_name: &str, parent: &str, id: u16) -> io::Result<()> {
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let (connection, handle, _) = new_connection()?;
+        tokio::spawn(connection);
+        let index = link_index(&handle, parent).await?;
+        handle
+            .link()
+            .add()
+            .vlan(link_name.to_string(), index, id)
+            .execute()
+            .await
+            .map_err(io::Error::other)
+    })
+}
+
+// only the properties given are changed
+pub fn set_link_properties(
+    link_name: &str,
+    mtu: Option<u32>,
+    mac: Option<[u8; 6]>,
+    txqueuelen: Option<u32>,
+    alias: Option<&str>,
+) -> io::Result<()> {
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let (connection, handle, _) = new_connection()?;
+        tokio::spawn(connection);
+        let index = link_index(&handle, link_name).await?;
+        let mut set = handle.link().set(index);
+        let attributes = &mut set.message_mut().attributes;
+        attributes.extend(mtu.map(LinkAttribute::Mtu));
+        attributes.extend(mac.map(|m| LinkAttribute::Address(m.to_vec())));
+        attributes.extend(txqueuelen.map(LinkAttribute::TxQueueLen));
+        attributes.extend(alias.map(|a| LinkAttribute::IfAlias(a.to_string())));
+        set.execute().await.map_err(io::Error::other)
+    })
+}
+
+// None when there is no such link in the current namespace
+pub fn link_mtu(link_name: &str) -> io::Result<Option<u32>> {
+    if !link_exists(link_name) {
+        return Ok(None);
+    }
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let (connection, handle, _) = new_connection()?;
+        tokio::spawn(connection);
+        let mut links = handle
+            .link()
+            .get()
+            .match_name(link_name.to_string())
+            .execute();
+        let link = links.try_next().await.map_err(io::Error::other)?;
+        Ok(link.and_then(|l| {
+            l.attributes.into_iter().find_map(|a| match a {
+                LinkAttribute::Mtu(mtu) => Some(mtu),
+                _ => None,
+            })
+        }))
+    })
+}
+
+// the largest MTU of the links in the current namespace. async, as the relay asks from
+// within its runtime
+pub async fn largest_mtu() -> io::Result<Option<u32>> {
+    let (connection, handle, _) = new_connection()?;
+    tokio::spawn(connection);
+    let mut links = handle.link().get().execute();
+    let mut largest = None;
+    while let Some(link) = links.try_next().await.map_err(io::Error::other)? {
+        let mtu = link.attributes.iter().find_map(|a| match a {
+            LinkAttribute::Mtu(mtu) => Some(*mtu),
+            _ => None,
+        });
+        largest = largest.max(mtu);
+    }
+    Ok(largest)
+}
+
+// makes `link_name` a port of the bridge `master`
+pub fn set_master(link_name: &str, master: &str) -> io::Result<()> {
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let (connection, handle, _) = new_connection()?;
+        tokio::spawn(connection);
+        let index = link_index(&handle, link_name).await?;
+        let master = link_index(&handle, master).await?;
+        handle
+            .link()
+            .set(index)
+            .controller(master)
+            .execute()
+            .await
+            .map_err(io::Error::other)
+    })
+}
+
+pub fn del_link_with_ns(link_name: &str, ns_name: &str) -> io::Result<()> {
+    in_ns(ns_name, || del_link(link_name))
+}
+
+async fn link_index(handle: &Handle, link_name: &str) -> io::Result<u32> {
+    let mut links = handle
+        .link()
+        .get()
+        .match_name(link_name.to_string())
+        .execute();
+    match links.try_next().await {
+        Ok(Some(link)) => Ok(link.header.index),
+        Ok(None) => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no link {}", link_name),
+        )),
+        Err(e) => Err(io::Error::other(format!("{}: {}", link_name, e))),
+    }
+}
+
 pub fn add_address<
     T: Into<String> + Clone + std::panic::UnwindSafe,
     U: Into<IpAddr> + Clone + std::panic::UnwindSafe,
@@ -83,7 +461,7 @@ pub fn add_address<
             .get()
             .match_name(link_name.clone().into())
             .execute();
-        if let Some(Some(link)) = links.try_next().await.ok() {
+        if let Ok(Some(link)) = links.try_next().await {
             debug!("link (add_address): {:?}", link);
             if let Err(e) = handle
                 .address()
@@ -91,7 +469,7 @@ pub fn add_address<
                 .execute()
                 .await
             {
-                return Err(io::Error::new(io::ErrorKind::Other, e));
+                return Err(io::Error::other(e));
             }
         }
         Ok::<(), io::Error>(())
@@ -109,52 +487,19 @@ pub fn add_address_with_ns<
     prefix: u8,
     ns_name: T,
 ) -> io::Result<()> {
-    let ns_path = format!("{}{}", NETNS_PATH, ns_name.into());
-    match unsafe { fork() } {
-        Ok(ForkResult::Parent { child, .. }) => {
-            waitpid(child, None).unwrap();
-        }
-        Ok(ForkResult::Child) => {
-            info!("(add_address_with_ns) fork child!");
-            let res = std::panic::catch_unwind(|| -> io::Result<()> {
-                let f = File::open(ns_path)?;
-                setns(f, CloneFlags::CLONE_NEWNET)?;
-                Ok(())
-            });
-            info!("(add_address_with_ns) changed ns!");
-            match res {
-                Err(_panic) => {
-                    log::error!("child process crashed");
-                    std::process::abort()
-                }
-                Ok(Err(fail)) => {
-                    log::error!("child process failed: {}", fail);
-                    exit(1)
-                }
-                Ok(Ok(())) => {
-                    info!("(add_address_with_ns) calling add_address");
-                    add_address(link_name, ip.into(), prefix)?;
-                    info!("(add_address_with_ns) child will exit 0");
-                    exit(0);
-                }
-            }
-        }
-        Err(_) => {
-            panic!("fork() failed");
-        }
-    }
-
-    Ok(())
+    in_ns(&ns_name.into(), || {
+        add_address(link_name.into(), ip.into(), prefix)
+    })
 }
 
 pub fn set_veth_to_ns<T: Into<String>>(link_name: T, ns_name: T) -> io::Result<()> {
-    let f = File::open(format!("{}{}", NETNS_PATH, ns_name.into()))?;
+    let f = File::open(netns_path(&ns_name.into()))?;
     let rt = tokio::runtime::Runtime::new()?;
     rt.block_on(async {
         let (connection, handle, _) = new_connection()?;
         tokio::spawn(connection);
         let mut links = handle.link().get().match_name(link_name.into()).execute();
-        if let Some(Some(link)) = links.try_next().await.ok() {
+        if let Ok(Some(link)) = links.try_next().await {
             if let Err(e) = handle
                 .link()
                 .set(link.header.index)
@@ -162,7 +507,7 @@ pub fn set_veth_to_ns<T: Into<String>>(link_name: T, ns_name: T) -> io::Result<(
                 .execute()
                 .await
             {
-                return Err(io::Error::new(io::ErrorKind::Other, e));
+                return Err(io::Error::other(e));
             }
         } else {
             info!("skipped");
@@ -178,10 +523,10 @@ pub fn set_link_up<T: Into<String> + std::panic::UnwindSafe>(link_name: T) -> io
         let (connection, handle, _) = new_connection()?;
         tokio::spawn(connection);
         let mut links = handle.link().get().match_name(link_name.into()).execute();
-        if let Some(Some(link)) = links.try_next().await.ok() {
+        if let Ok(Some(link)) = links.try_next().await {
             debug!("link (set_link_up) {:?}", link);
             if let Err(e) = handle.link().set(link.header.index).up().execute().await {
-                return Err(io::Error::new(io::ErrorKind::Other, e));
+                return Err(io::Error::other(e));
             }
         }
         Ok::<(), io::Error>(())
@@ -193,38 +538,8 @@ pub fn set_link_up_with_ns<T: Into<String> + Clone + std::panic::UnwindSafe>(
     link_name: T,
     ns_name: T,
 ) -> io::Result<()> {
-    let ns_path = format!("{}{}", NETNS_PATH, ns_name.into());
-    match unsafe { fork() } {
-        Ok(ForkResult::Parent { child, .. }) => {
-            waitpid(child, None).unwrap();
-        }
-        Ok(ForkResult::Child) => {
-            info!("(set_link_up_with_ns) fork child!");
-            let res = std::panic::catch_unwind(|| -> io::Result<()> {
-                let f = File::open(ns_path)?;
-                setns(f, CloneFlags::CLONE_NEWNET)?;
-                Ok(())
-            });
-            match res {
-                Err(_panic) => {
-                    log::error!("child process crashed");
-                    std::process::abort()
-                }
-                Ok(Err(fail)) => {
-                    log::error!("child process failed: {}", fail);
-                    exit(1)
-                }
-                Ok(Ok(())) => {
-                    set_link_up("lo")?;
-                    set_link_up(link_name)?;
-                    exit(0);
-                }
-            }
-        }
-        Err(_) => {
-            panic!("fork() failed");
-        }
-    }
-
-    Ok(())
+    in_ns(&ns_name.into(), || {
+        set_link_up("lo")?;
+        set_link_up(link_name)
+    })
 }
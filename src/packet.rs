@@ -1,4 +1,43 @@
-use dhcproto::v4::Message;
+use std::{io, net::Ipv4Addr, ops::Range};
+
+use dhcproto::{
+    error::DecodeResult,
+    v4::{DhcpOption, DhcpOptions, Message, MessageType, OptionCode},
+    Decodable, Decoder, Encodable, Encoder,
+};
+
+// largest UDP payload in an IPv4 datagram
+pub const MAX_DATAGRAM: usize = 65507;
+// every DHCP client accepts datagrams up to this size, IP and UDP headers included
+// ref: https://www.rfc-editor.org/rfc/rfc2131#section-2
+pub const MIN_MESSAGE_SIZE: usize = 576;
+// fixed BOOTP header, without and with the magic cookie
+const BOOTP_HEADER_LEN: usize = 236;
+const HEADER_LEN: usize = 240;
+pub const CIADDR: Range<usize> = 12..16;
+pub const GIADDR: Range<usize> = 24..28;
+const SNAME: Range<usize> = 44..108;
+const FILE: Range<usize> = 108..236;
+// IPv4 and UDP headers
+const IP_UDP_HEADER_LEN: usize = 28;
+// message type (and rule) of datagrams that did not decode
+pub const UNDECODABLE: &str = "undecodable";
+// message type of BOOTP messages
+pub const BOOTP: &str = "bootp";
+// RFC 1048 vend field
+// ref: https://www.rfc-editor.org/rfc/rfc1048
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+// receive buffer for a link MTU: a datagram that does not fit came in fragments and is
+// reported as truncated. without a known MTU, anything UDP can carry fits.
+pub fn buffer_size(mtu: Option<u32>) -> usize {
+    match mtu {
+        Some(mtu) => (mtu as usize)
+            .saturating_sub(IP_UDP_HEADER_LEN)
+            .clamp(MIN_MESSAGE_SIZE - IP_UDP_HEADER_LEN, MAX_DATAGRAM),
+        None => MAX_DATAGRAM,
+    }
+}
 
 #[derive(Debug)]
 pub struct DHCPMessage(Message);
@@ -7,6 +46,196 @@ impl DHCPMessage {
     pub fn raw(&self) -> Message {
         self.0.clone()
     }
+
+    pub fn raw_mut(&mut self) -> &mut Message {
+        &mut self.0
+    }
+
+    pub fn xid(&self) -> u32 {
+        self.0.xid()
+    }
+
+    pub fn msg_type(&self) -> Option<MessageType> {
+        self.0.opts().msg_type()
+    }
+
+    // lowercase message type for logs and metric labels
+    pub fn msg_type_name(&self) -> String {
+        match self.msg_type() {
+            Some(MessageType::Unknown(n)) => format!("unknown_{}", n),
+            Some(t) => format!("{:?}", t).to_lowercase(),
+            None => String::from(BOOTP),
+        }
+    }
+
+    // plain BOOTP (RFC 951): no DHCP message type, with or without the magic cookie
+    pub fn is_bootp(&self) -> bool {
+        self.msg_type().is_none()
+    }
+
+    // chaddr trimmed to hlen (6 bytes for ethernet)
+    pub fn chaddr(&self) -> &[u8] {
+        let chaddr = self.0.chaddr();
+        let hlen = usize::from(self.0.hlen()).min(chaddr.len());
+        &chaddr[..hlen]
+    }
+
+    // option 61
+    pub fn client_id(&self) -> Option<&[u8]> {
+        match self.0.opts().get(OptionCode::ClientIdentifier) {
+            Some(DhcpOption::ClientIdentifier(v)) => Some(v),
+            _ => None,
+        }
+    }
+
+    // option 60
+    pub fn vendor_class(&self) -> Option<&[u8]> {
+        match self.0.opts().get(OptionCode::ClassIdentifier) {
+            Some(DhcpOption::ClassIdentifier(v)) => Some(v),
+            _ => None,
+        }
+    }
+
+    // option 93
+    // ref: https://www.rfc-editor.org/rfc/rfc4578#section-2.1
+    pub fn client_arch(&self) -> Option<u16> {
+        match self.0.opts().get(OptionCode::ClientSystemArchitecture) {
+            Some(DhcpOption::ClientSystemArchitecture(a)) => Some(u16::from(*a)),
+            _ => None,
+        }
+    }
+
+    // option 77
+    // ref: https://www.rfc-editor.org/rfc/rfc3004#section-4
+    // some clients (e.g. iPXE) send the class data without the length prefix,
+    // so fall back to the whole payload when it does not parse as a list.
+    pub fn user_classes(&self) -> Vec<&[u8]> {
+        let data = match self.0.opts().get(OptionCode::UserClass) {
+            Some(DhcpOption::UserClass(v)) => v.as_slice(),
+            _ => return Vec::new(),
+        };
+        let mut classes = Vec::new();
+        let mut rest = data;
+        while let Some((&len, tail)) = rest.split_first() {
+            let len = usize::from(len);
+            if len == 0 || len > tail.len() {
+                return vec![data];
+            }
+            classes.push(&tail[..len]);
+            rest = &tail[len..];
+        }
+        classes
+    }
+
+    // option 57; the IP and UDP headers count towards it
+    // ref: https://www.rfc-editor.org/rfc/rfc2132#section-9.10
+    pub fn max_message_size(&self) -> usize {
+        let floor = MIN_MESSAGE_SIZE - IP_UDP_HEADER_LEN;
+        match self.0.opts().get(OptionCode::MaxMessageSize) {
+            Some(DhcpOption::MaxMessageSize(n)) => {
+                usize::from(*n).saturating_sub(IP_UDP_HEADER_LEN).max(floor)
+            }
+            _ => floor,
+        }
+    }
+
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.0
+            .encode(&mut Encoder::new(&mut buf))
+            .map_err(io::Error::other)?;
+        Ok(buf)
+    }
+
+    // encodes into at most `max_size` bytes. options that do not fit are moved into
+    // unused `file` and `sname` fields (option 52), in this order. option 82 stays last in
+    // the options field, right before End.
+    // ref: https://www.rfc-editor.org/rfc/rfc2132#section-9.3
+    // ref: https://www.rfc-editor.org/rfc/rfc3046#section-2.1
+    pub fn encode_within(&self, max_size: usize) -> io::Result<Vec<u8>> {
+        let buf = self.encode()?;
+        if buf.len() <= max_size {
+            return Ok(buf);
+        }
+        let too_large = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("message is {} bytes, limit is {}", buf.len(), max_size),
+            )
+        };
+        let opts = self.0.opts();
+        if opts.get(OptionCode::OptionOverload).is_some() {
+            return Err(too_large());
+        }
+
+        let mut relay_info = Vec::new();
+        if let Some(opt) = opts.get(OptionCode::RelayAgentInformation) {
+            opt.encode(&mut Encoder::new(&mut relay_info))
+                .map_err(io::Error::other)?;
+        }
+        // (capacity without the End option, overload flag, encoded options)
+        let is_unused = |f: Option<&[u8]>| !f.is_some_and(|f| f.iter().any(|b| *b != 0));
+        let main_cap = max_size.saturating_sub(HEADER_LEN + 3 + relay_info.len() + 1);
+        let mut areas = vec![(main_cap, 0u8, Vec::new())];
+        if is_unused(self.0.fname()) {
+            areas.push((FILE.len() - 1, 1, Vec::new()));
+        }
+        if is_unused(self.0.sname()) {
+            areas.push((SNAME.len() - 1, 2, Vec::new()));
+        }
+        // the message type goes first so clients find it in the options field
+        let ordered = opts
+            .get(OptionCode::MessageType)
+            .into_iter()
+            .chain(opts.iter().filter_map(|(code, opt)| match code {
+                OptionCode::MessageType
+                | OptionCode::RelayAgentInformation
+                | OptionCode::End
+                | OptionCode::Pad => None,
+                _ => Some(opt),
+            }));
+        for opt in ordered {
+            let mut encoded = Vec::new();
+            opt.encode(&mut Encoder::new(&mut encoded))
+                .map_err(io::Error::other)?;
+            let area = areas
+                .iter_mut()
+                .find(|(cap, _, used)| used.len() + encoded.len() <= *cap)
+                .ok_or_else(too_large)?;
+            area.2.extend(encoded);
+        }
+
+        let mut header = self.0.clone();
+        header.set_opts(DhcpOptions::new());
+        let mut out = Vec::with_capacity(max_size);
+        header
+            .encode(&mut Encoder::new(&mut out))
+            .map_err(io::Error::other)?;
+        let mut overload = 0;
+        let mut main = Vec::new();
+        for (_, flag, used) in areas {
+            if flag == 0 {
+                main = used;
+                continue;
+            }
+            if used.is_empty() {
+                continue;
+            }
+            overload |= flag;
+            let range = if flag == 1 { FILE } else { SNAME };
+            let field = &mut out[range];
+            field.fill(0);
+            field[..used.len()].copy_from_slice(&used);
+            field[used.len()] = u8::from(OptionCode::End);
+        }
+        if overload != 0 {
+            out.extend([u8::from(OptionCode::OptionOverload), 1, overload]);
+        }
+        out.extend(main);
+        out.extend(relay_info);
+        out.push(u8::from(OptionCode::End));
+        Ok(out)
+    }
 }
 
 impl From<Message> for DHCPMessage {
@@ -14,3 +243,270 @@ impl From<Message> for DHCPMessage {
         DHCPMessage(value)
     }
 }
+
+pub fn format_mac(mac: &[u8]) -> String {
+    mac.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+// BOOTP messages may end right after the fixed header (no vend field);
+// those decode as if the vend field were zeros
+pub fn decode(buf: &[u8]) -> DecodeResult<Message> {
+    if (BOOTP_HEADER_LEN..HEADER_LEN).contains(&buf.len()) {
+        let mut padded = buf.to_vec();
+        padded.resize(HEADER_LEN, 0);
+        return Message::decode(&mut Decoder::new(&padded));
+    }
+    Message::decode(&mut Decoder::new(buf))
+}
+
+// a BOOTP message whose vend field is not RFC 1048 options (no magic cookie)
+pub fn is_plain_bootp(buf: &[u8]) -> bool {
+    buf.len() >= BOOTP_HEADER_LEN && buf.get(BOOTP_HEADER_LEN..HEADER_LEN) != Some(&MAGIC_COOKIE)
+}
+
+// the checks an undecodable message has to pass before it is forwarded as-is
+pub fn check_bootp(buf: &[u8], op: u8) -> Result<(), &'static str> {
+    if buf.len() < BOOTP_HEADER_LEN {
+        return Err("shorter than the BOOTP header");
+    }
+    if buf[0] != op {
+        return Err("unexpected op");
+    }
+    if buf[2] > 16 {
+        return Err("hlen larger than chaddr");
+    }
+    Ok(())
+}
+
+// 16 bytes per line, with offsets
+pub fn hex_dump(buf: &[u8]) -> String {
+    buf.chunks(16)
+        .enumerate()
+        .map(|(i, line)| {
+            let bytes: Vec<_> = line.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("{:04x}  {}", i * 16, bytes.join(" "))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// xid and chaddr straight from the fixed BOOTP header, before the message is decoded
+pub fn peek_header(buf: &[u8]) -> Option<(u32, &[u8])> {
+    if buf.len() < 44 {
+        return None;
+    }
+    let xid = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+    let hlen = usize::from(buf[2]).min(16);
+    Some((xid, &buf[28..28 + hlen]))
+}
+
+// sets giaddr of an encoded request unless a relay agent before us did; true when set
+pub fn fill_giaddr(buf: &mut [u8], giaddr: Ipv4Addr) -> bool {
+    if buf[GIADDR].iter().any(|b| *b != 0) {
+        return false;
+    }
+    buf[GIADDR].copy_from_slice(&giaddr.octets());
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use dhcproto::v4::{
+        relay::{RelayAgentInformation, RelayInfo},
+        UnknownOption,
+    };
+
+    use super::*;
+
+    fn discover() -> Message {
+        let mut msg = Message::new(
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Addr::UNSPECIFIED,
+            &[0x02, 0, 0, 0, 0, 1],
+        );
+        msg.set_xid(0x1234_5678);
+        msg.opts_mut()
+            .insert(DhcpOption::MessageType(MessageType::Discover));
+        msg
+    }
+
+    // a message with `count` 30-byte site-specific options and option 82
+    fn large(count: u8) -> Message {
+        let mut msg = discover();
+        for code in 0..count {
+            msg.opts_mut()
+                .insert(DhcpOption::Unknown(UnknownOption::new(
+                    OptionCode::from(224 + code),
+                    vec![code; 30],
+                )));
+        }
+        let mut info = RelayAgentInformation::default();
+        info.insert(RelayInfo::AgentCircuitId(b"eth0".to_vec()));
+        msg.opts_mut()
+            .insert(DhcpOption::RelayAgentInformation(info));
+        msg
+    }
+
+    // option codes of an area, up to End
+    fn codes(mut area: &[u8]) -> Vec<u8> {
+        let mut codes = Vec::new();
+        while let Some((&code, rest)) = area.split_first() {
+            match code {
+                0 => area = rest,
+                255 => return codes,
+                _ => {
+                    codes.push(code);
+                    let len = usize::from(rest[0]);
+                    area = &rest[1 + len..];
+                }
+            }
+        }
+        panic!("no End option");
+    }
+
+    #[test]
+    fn encode_within_keeps_messages_that_fit() {
+        let msg = DHCPMessage::from(large(2));
+        let plain = msg.encode().unwrap();
+        assert_eq!(msg.encode_within(plain.len()).unwrap(), plain);
+    }
+
+    #[test]
+    fn encode_within_overloads_file_then_sname() {
+        let msg = DHCPMessage::from(large(13));
+        let max = MIN_MESSAGE_SIZE - IP_UDP_HEADER_LEN;
+        assert!(msg.encode().unwrap().len() > max);
+        let buf = msg.encode_within(max).unwrap();
+        assert!(buf.len() <= max);
+        assert_eq!(buf[BOOTP_HEADER_LEN..HEADER_LEN], MAGIC_COOKIE);
+
+        let main = codes(&buf[HEADER_LEN..]);
+        assert!(main.contains(&u8::from(OptionCode::MessageType)));
+        assert_eq!(
+            main.last(),
+            Some(&u8::from(OptionCode::RelayAgentInformation))
+        );
+        let file = codes(&buf[FILE]);
+        let sname = codes(&buf[SNAME]);
+        assert_eq!(file.len(), 3);
+        assert_eq!(sname.len(), 1);
+        let moved = main.iter().chain(&file).chain(&sname);
+        assert_eq!(moved.filter(|c| (224..237).contains(*c)).count(), 13);
+
+        let decoded = decode(&buf).unwrap();
+        assert_eq!(decoded.xid(), msg.xid());
+        assert_eq!(
+            decoded.opts().get(OptionCode::OptionOverload),
+            Some(&DhcpOption::OptionOverload(3))
+        );
+    }
+
+    #[test]
+    fn encode_within_leaves_used_fields_alone() {
+        let mut msg = large(10);
+        msg.set_fname_str("pxelinux.0");
+        let msg = DHCPMessage::from(msg);
+        let max = MIN_MESSAGE_SIZE - IP_UDP_HEADER_LEN;
+        let buf = msg.encode_within(max).unwrap();
+        assert!(buf.len() <= max);
+        assert!(buf[FILE].starts_with(b"pxelinux.0\0"));
+        assert!(!codes(&buf[SNAME]).is_empty());
+    }
+
+    #[test]
+    fn encode_within_fails_when_nothing_fits() {
+        let msg = DHCPMessage::from(large(20));
+        let err = msg.encode_within(300).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn max_message_size_has_a_floor() {
+        let mut msg = discover();
+        assert_eq!(DHCPMessage::from(msg.clone()).max_message_size(), 548);
+        msg.opts_mut().insert(DhcpOption::MaxMessageSize(1500));
+        assert_eq!(DHCPMessage::from(msg.clone()).max_message_size(), 1472);
+        msg.opts_mut().insert(DhcpOption::MaxMessageSize(300));
+        assert_eq!(DHCPMessage::from(msg).max_message_size(), 548);
+    }
+
+    #[test]
+    fn user_classes_fall_back_to_the_payload() {
+        let mut msg = discover();
+        msg.opts_mut()
+            .insert(DhcpOption::UserClass(b"\x04iPXE\x03foo".to_vec()));
+        let classes: Vec<&[u8]> = vec![b"iPXE", b"foo"];
+        assert_eq!(DHCPMessage::from(msg.clone()).user_classes(), classes);
+        msg.opts_mut()
+            .insert(DhcpOption::UserClass(b"iPXE".to_vec()));
+        let classes: Vec<&[u8]> = vec![b"iPXE"];
+        assert_eq!(DHCPMessage::from(msg).user_classes(), classes);
+    }
+
+    #[test]
+    fn peek_header_reads_xid_and_chaddr() {
+        let mut buf = Vec::new();
+        discover().encode(&mut Encoder::new(&mut buf)).unwrap();
+        assert_eq!(
+            peek_header(&buf),
+            Some((0x1234_5678, &[0x02, 0, 0, 0, 0, 1][..]))
+        );
+        // hlen larger than chaddr
+        buf[2] = 200;
+        assert_eq!(peek_header(&buf).map(|(_, chaddr)| chaddr.len()), Some(16));
+        assert_eq!(peek_header(&buf[..40]), None);
+    }
+
+    #[test]
+    fn buffer_size_fits_the_mtu() {
+        assert_eq!(buffer_size(None), MAX_DATAGRAM);
+        assert_eq!(buffer_size(Some(1500)), 1472);
+        assert_eq!(buffer_size(Some(68)), 548);
+        assert_eq!(buffer_size(Some(u32::MAX)), MAX_DATAGRAM);
+    }
+
+    #[test]
+    fn check_bootp_headers() {
+        let buf = DHCPMessage::from(discover()).encode().unwrap();
+        assert_eq!(check_bootp(&buf, 1), Ok(()));
+        assert!(check_bootp(&buf, 2).is_err());
+        assert!(check_bootp(&buf[..200], 1).is_err());
+        let mut bad_hlen = buf.clone();
+        bad_hlen[2] = 17;
+        assert!(check_bootp(&bad_hlen, 1).is_err());
+    }
+
+    #[test]
+    fn decode_pads_bootp_without_vend() {
+        let mut buf = DHCPMessage::from(discover()).encode().unwrap();
+        buf.truncate(BOOTP_HEADER_LEN);
+        let msg = DHCPMessage::from(decode(&buf).unwrap());
+        assert!(msg.is_bootp());
+        assert_eq!(msg.msg_type_name(), BOOTP);
+        assert_eq!(msg.xid(), 0x1234_5678);
+        assert!(decode(&buf[..200]).is_err());
+    }
+
+    #[test]
+    fn plain_bootp_has_no_magic_cookie() {
+        let mut buf = DHCPMessage::from(discover()).encode().unwrap();
+        assert!(!is_plain_bootp(&buf));
+        buf[BOOTP_HEADER_LEN..HEADER_LEN].fill(0);
+        assert!(is_plain_bootp(&buf));
+        assert!(is_plain_bootp(&buf[..BOOTP_HEADER_LEN]));
+        assert!(!is_plain_bootp(&buf[..200]));
+    }
+
+    #[test]
+    fn fill_giaddr_keeps_an_earlier_relay() {
+        let mut buf = DHCPMessage::from(discover()).encode().unwrap();
+        assert!(fill_giaddr(&mut buf, Ipv4Addr::new(10, 0, 0, 1)));
+        assert!(!fill_giaddr(&mut buf, Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(buf[GIADDR], [10, 0, 0, 1]);
+    }
+}
@@ -0,0 +1,496 @@
+use std::{collections::HashMap, fmt, io, net::Ipv4Addr};
+
+use ipnet::Ipv4Net;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    config::{Config, LinkBackend, PeerAddress},
+    helper, needs_netns,
+    network::{
+        add_address, add_address_with_ns, add_ns, add_route, add_route_with_ns, add_rule_with_ns,
+        create_ipvlan, create_macvlan, create_veth_pair, create_vlan, del_link, del_link_with_ns,
+        link_addresses, link_exists, link_mtu, netns_path, set_link_properties, set_link_up,
+        set_link_up_with_ns, set_master, set_veth_to_ns,
+    },
+    new_route,
+    packet::format_mac,
+    plan_ns,
+    process::tokens,
+    route::RouteInfo,
+    state::Resource,
+    vlan,
+    watch::{link_name, subnet_for},
+};
+
+// one netlink operation of `setup_ns`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+pub enum Step {
+    AddNetns {
+        name: String,
+    },
+    CreateVeth {
+        link: String,
+        peer: String,
+    },
+    // a macvlan or ipvlan child of `parent`
+    CreateChild {
+        link: String,
+        parent: String,
+        kind: ChildKind,
+    },
+    // properties of a link just created, set before it is moved or brought up
+    SetLink {
+        link: String,
+        mtu: Option<u32>,
+        mac: Option<MacAddr>,
+        txqueuelen: Option<u32>,
+        alias: Option<String>,
+    },
+    SetMaster {
+        link: String,
+        master: String,
+    },
+    CreateVlan {
+        link: String,
+        parent: String,
+        id: u16,
+    },
+    MoveToNetns {
+        link: String,
+        netns: String,
+    },
+    AddAddress {
+        link: String,
+        netns: Option<String>,
+        address: Ipv4Addr,
+        prefix: u8,
+    },
+    LinkUp {
+        link: String,
+        netns: Option<String>,
+    },
+    AddRoute {
+        destination: Ipv4Addr,
+        prefix: u8,
+        gateway: Ipv4Addr,
+        table: Option<u32>,
+        metric: Option<u32>,
+        netns: Option<String>,
+    },
+    AddRule {
+        from: Ipv4Addr,
+        table: u32,
+        netns: String,
+    },
+    // removes an attachment, or undoes a link of a failed setup
+    DelLink {
+        link: String,
+        netns: Option<String>,
+    },
+}
+
+impl Step {
+    // (kind, name) for `middle_sock_setup_status`
+    pub fn label(&self) -> (&'static str, String) {
+        match self {
+            Step::AddNetns { name } => ("netns", name.clone()),
+            Step::CreateVeth { link, peer } => ("veth", format!("{}/{}", link, peer)),
+            Step::CreateChild { link, kind, .. } => (kind.label(), link.clone()),
+            Step::SetLink { link, .. } => ("link", link.clone()),
+            Step::SetMaster { link, .. } => ("master", link.clone()),
+            Step::CreateVlan { link, .. } => ("vlan", link.clone()),
+            Step::MoveToNetns { link, .. } => ("veth_netns", link.clone()),
+            Step::AddAddress { link, .. } => ("address", link.clone()),
+            Step::LinkUp { link, .. } => ("link_up", link.clone()),
+            Step::AddRoute { destination, .. } => ("route", destination.to_string()),
+            Step::AddRule { from, .. } => ("rule", from.to_string()),
+            Step::DelLink { link, .. } => ("del_link", link.clone()),
+        }
+    }
+
+    // in the namespace helper when it runs, as the steps fork into other namespaces
+    pub fn run(&self) -> io::Result<()> {
+        match helper::get() {
+            Some(helper) => helper.run(self),
+            None => self.apply(),
+        }
+    }
+
+    pub(crate) fn apply(&self) -> io::Result<()> {
+        match self.clone() {
+            Step::AddNetns { name } => add_ns(name),
+            Step::CreateVeth { link, peer } => create_veth_pair(link, peer),
+            Step::CreateChild {
+                link,
+                parent,
+                kind: ChildKind::Macvlan,
+            } => create_macvlan(&link, &parent),
+            Step::CreateChild {
+                link,
+                parent,
+                kind: ChildKind::Ipvlan,
+            } => create_ipvlan(&link, &parent),
+            Step::SetLink {
+                link,
+                mtu,
+                mac,
+                txqueuelen,
+                alias,
+            } => set_link_properties(&link, mtu, mac.map(|m| m.0), txqueuelen, alias.as_deref()),
+            Step::SetMaster { link, master } => set_master(&link, &master),
+            Step::CreateVlan { link, parent, id } => create_vlan(&link, &parent, id),
+            Step::MoveToNetns { link, netns } => set_veth_to_ns(link, netns),
+            Step::AddAddress {
+                link,
+                netns: None,
+                address,
+                prefix,
+            } => add_address(link, address, prefix),
+            Step::AddAddress {
+                link,
+                netns: Some(netns),
+                address,
+                prefix,
+            } => add_address_with_ns(link, address, prefix, netns),
+            Step::LinkUp { link, netns: None } => set_link_up(link),
+            Step::LinkUp {
+                link,
+                netns: Some(netns),
+            } => set_link_up_with_ns(link, netns),
+            Step::AddRoute {
+                destination,
+                prefix,
+                gateway,
+                netns: None,
+                ..
+            } => add_route(destination, prefix, gateway),
+            Step::AddRoute {
+                destination,
+                prefix,
+                gateway,
+                table,
+                metric,
+                netns: Some(netns),
+            } => add_route_with_ns(destination, prefix, gateway, table, metric, &netns),
+            Step::AddRule { from, table, netns } => add_rule_with_ns(from, table, &netns),
+            Step::DelLink { link, netns: None } => del_link(link),
+            Step::DelLink {
+                link,
+                netns: Some(netns),
+            } => del_link_with_ns(&link, &netns),
+        }
+    }
+
+    // what the step leaves behind, as listed by `ctl links`
+    pub fn resource(&self) -> Option<Resource> {
+        match self.clone() {
+            Step::AddNetns { name } => Some(Resource::Netns(name)),
+            Step::AddAddress {
+                link,
+                netns,
+                address,
+                prefix,
+            } => Some(Resource::Link {
+                name: link,
+                netns,
+                address,
+                prefix,
+            }),
+            Step::AddRoute {
+                destination,
+                prefix,
+                gateway,
+                table,
+                netns,
+                ..
+            } => Some(Resource::Route {
+                destination,
+                prefix,
+                gateway,
+                table,
+                netns,
+            }),
+            Step::AddRule { from, table, netns } => Some(Resource::Rule { from, table, netns }),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let netns = |ns: &Option<String>| ns.as_deref().unwrap_or("-").to_string();
+        match self {
+            Step::AddNetns { name } => write!(f, "add netns {}", name),
+            Step::CreateVeth { link, peer } => write!(f, "create veth {} peer {}", link, peer),
+            Step::CreateChild { link, parent, kind } => {
+                write!(f, "create {} {} on {}", kind.label(), link, parent)
+            }
+            Step::SetLink {
+                link,
+                mtu,
+                mac,
+                txqueuelen,
+                alias,
+            } => {
+                write!(f, "set {}", link)?;
+                if let Some(mtu) = mtu {
+                    write!(f, " mtu {}", mtu)?;
+                }
+                if let Some(mac) = mac {
+                    write!(f, " address {}", mac)?;
+                }
+                if let Some(txqueuelen) = txqueuelen {
+                    write!(f, " txqueuelen {}", txqueuelen)?;
+                }
+                if let Some(alias) = alias {
+                    write!(f, " alias {}", alias)?;
+                }
+                Ok(())
+            }
+            Step::SetMaster { link, master } => write!(f, "add {} to bridge {}", link, master),
+            Step::CreateVlan { link, parent, id } => {
+                write!(f, "create vlan {} id {} on {}", link, id, parent)
+            }
+            Step::MoveToNetns { link, netns } => write!(f, "move {} to netns {}", link, netns),
+            Step::AddAddress {
+                link,
+                netns: ns,
+                address,
+                prefix,
+            } => write!(
+                f,
+                "add address {}/{} to {} netns={}",
+                address,
+                prefix,
+                link,
+                netns(ns)
+            ),
+            Step::LinkUp { link, netns: ns } => write!(f, "set {} up netns={}", link, netns(ns)),
+            Step::AddRoute {
+                destination,
+                prefix,
+                gateway,
+                table,
+                metric,
+                netns: ns,
+            } => {
+                write!(f, "add route {}/{} via {}", destination, prefix, gateway)?;
+                if let Some(table) = table {
+                    write!(f, " table {}", table)?;
+                }
+                if let Some(metric) = metric {
+                    write!(f, " metric {}", metric)?;
+                }
+                write!(f, " netns={}", netns(ns))
+            }
+            Step::AddRule { from, table, netns } => {
+                write!(f, "add rule from {} table {} netns={}", from, table, netns)
+            }
+            Step::DelLink { link, netns: ns } => {
+                write!(f, "delete link {} netns={}", link, netns(ns))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    // FNV-1a of "<netns>/<link>", as a locally administered unicast address
+    // ref: https://datatracker.ietf.org/doc/html/draft-eastlake-fnv
+    pub fn stable(netns: &str, link: &str) -> Self {
+        let hash = format!("{}/{}", netns, link)
+            .bytes()
+            .fold(0xcbf29ce484222325u64, |h, b| {
+                (h ^ u64::from(b)).wrapping_mul(0x100000001b3)
+            });
+        let mut mac = [0; 6];
+        mac.copy_from_slice(&hash.to_be_bytes()[2..]);
+        mac[0] = (mac[0] & 0xfe) | 0x02;
+        Self(mac)
+    }
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", format_mac(&self.0))
+    }
+}
+
+impl Serialize for MacAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for MacAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        let octets = s
+            .split(':')
+            .map(|o| u8::from_str_radix(o, 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(de::Error::custom)?;
+        let mac = octets
+            .try_into()
+            .map_err(|_| de::Error::custom(format!("invalid MAC address: {}", s)))?;
+        Ok(Self(mac))
+    }
+}
+
+// what `plan_ns` needs to know about the namespace middle-sock runs in
+#[derive(Debug, Clone, Default)]
+pub struct Host {
+    pub create_netns: bool,
+    // addresses the peer must not take
+    pub used: Vec<Ipv4Addr>,
+    // MTU of the interface, when it is a link here
+    pub mtu: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChildKind {
+    Macvlan,
+    Ipvlan,
+}
+
+impl ChildKind {
+    fn label(&self) -> &'static str {
+        match self {
+            ChildKind::Macvlan => "macvlan",
+            ChildKind::Ipvlan => "ipvlan",
+        }
+    }
+}
+
+// what middle-sock would do on this host, computed without touching it
+#[derive(Debug, Serialize)]
+pub struct Plan {
+    pub netns: String,
+    pub interfaces: Vec<InterfacePlan>,
+    // argv of the child process and the namespace it is spawned in
+    pub command: Option<Vec<String>>,
+    pub command_netns: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InterfacePlan {
+    pub name: String,
+    pub backend: String,
+    pub subnets: Vec<Ipv4Net>,
+    pub gateway: Option<Ipv4Addr>,
+    // why the interface is left alone
+    pub skipped: Option<String>,
+    pub steps: Vec<Step>,
+    pub notes: Vec<String>,
+}
+
+impl Plan {
+    pub fn new(config: &Config, ip: Ipv4Addr, command: Option<&str>) -> io::Result<Self> {
+        let network = &config.network;
+        let mut route_info: Vec<(String, RouteInfo)> = {
+            let mut map = HashMap::new();
+            for r in new_route(network.route_path())? {
+                r.parse_network(&mut map)?;
+            }
+            vlan::merge(&network.vlans, &mut map);
+            map.into_iter().collect()
+        };
+        route_info.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut netns_created = !needs_netns(network)?;
+        let mut links: Vec<String> = Vec::new();
+        let mut interfaces = Vec::new();
+        for (iface, info) in route_info {
+            let mut plan = InterfacePlan {
+                name: iface.clone(),
+                backend: network.backend_for(&iface).to_string(),
+                subnets: info.subnets.iter().map(|e| e.destination).collect(),
+                gateway: info.gateway(),
+                skipped: None,
+                steps: Vec::new(),
+                notes: Vec::new(),
+            };
+            // relayed by middle-sock itself; the server reaches giaddr over the other links
+            if let Some(vlan) = network.vlan(&iface) {
+                plan.backend = String::from("vlan");
+                plan.steps = vlan::steps(vlan, link_exists(&iface), &link_addresses(&iface)?);
+                plan.notes.push(format!(
+                    "vlan {} on {}: giaddr {}, circuit id {:?}",
+                    vlan.id,
+                    vlan.parent,
+                    vlan.address.addr(),
+                    vlan.circuit_id()
+                ));
+                interfaces.push(plan);
+                continue;
+            }
+            let subnet = subnet_for(&info, ip);
+            match (subnet, info.is_full()) {
+                (Some(_), true) => {
+                    let taken: Vec<&str> = links.iter().map(String::as_str).collect();
+                    let link = link_name(&network.link, &taken);
+                    let host = Host {
+                        create_netns: !netns_created,
+                        used: Vec::new(),
+                        mtu: link_mtu(&iface)?,
+                    };
+                    plan.steps = plan_ns(&link, &iface, ip, &info, network, &host)?;
+                    let veth = *network.backend_for(&iface) == LinkBackend::Veth;
+                    if veth && matches!(network.peer, PeerAddress::Auto | PeerAddress::LinkLocal(_))
+                    {
+                        plan.notes.push(String::from(
+                            "peer address picked without looking at addresses in use",
+                        ));
+                    }
+                    netns_created = true;
+                    links.push(link);
+                }
+                _ if info.subnets.is_empty() => {
+                    plan.skipped = Some(String::from("no connected subnet"))
+                }
+                _ => plan.skipped = Some(String::from("no default route")),
+            }
+            interfaces.push(plan);
+        }
+        Ok(Self {
+            netns: network.netns.to_string(),
+            interfaces,
+            command: command.map(tokens),
+            command_netns: netns_path(network.netns.as_str()).display().to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for i in &self.interfaces {
+            let subnets: Vec<_> = i.subnets.iter().map(|s| s.to_string()).collect();
+            write!(
+                f,
+                "{} backend={} subnets=[{}] gateway={}",
+                i.name,
+                i.backend,
+                subnets.join(", "),
+                i.gateway.map_or(String::from("-"), |g| g.to_string())
+            )?;
+            match &i.skipped {
+                Some(reason) => writeln!(f, ": skipped ({})", reason)?,
+                None => writeln!(f)?,
+            }
+            for step in &i.steps {
+                writeln!(f, "  {}", step)?;
+            }
+            for note in &i.notes {
+                writeln!(f, "  note: {}", note)?;
+            }
+        }
+        match &self.command {
+            Some(argv) => writeln!(f, "spawn {:?} in {}", argv, self.command_netns),
+            None => writeln!(f, "no command given"),
+        }
+    }
+}
@@ -1,46 +1,120 @@
 use std::{
     fs::File,
-    io,
+    io::{self, BufRead, BufReader, Read},
     os::unix::prelude::CommandExt,
-    process::{Command, Stdio},
+    process::{Child, Command, Stdio},
+    thread,
 };
 
-use log::info;
+use crate::{
+    metrics::{metrics, CHILD_ADMIN_RESTARTS},
+    network::netns_path,
+};
+use log::{info, warn, Level};
 use nix::sched::{setns, CloneFlags};
-use rtnetlink::NETNS_PATH;
 
 #[derive(Debug)]
 pub struct ProcessExecutor {
     command: Command,
+    child: Option<Child>,
+    netns_name: Option<String>,
 }
 
 impl ProcessExecutor {
     pub fn new<T: Into<String>>(cmd: T) -> Self {
-        let cmd: String = cmd.into();
-        let replaced_string = cmd.replace("'", "");
-        let tokens: Vec<_> = replaced_string.split_whitespace().collect();
-        let mut builder = Command::new(tokens[0]);
+        let tokens = tokens(&cmd.into());
+        let mut builder = Command::new(&tokens[0]);
         if let Some(args) = tokens.get(1..) {
             builder.args(args);
         }
-        Self { command: builder }
+        Self {
+            command: builder,
+            child: None,
+            netns_name: None,
+        }
     }
 
     pub fn run<T: Into<String>>(&mut self, netns_name: T) -> io::Result<()> {
-        let ns_path = format!("{}{}", NETNS_PATH, netns_name.into());
+        let netns_name: String = netns_name.into();
+        // opened here, as the forked child may only make syscalls: other threads of a
+        // running runtime could hold the allocator's lock when it forks
+        let ns = File::open(netns_path(&netns_name))?;
 
-        let child = unsafe {
+        let mut child = unsafe {
             self.command
                 .stdout(Stdio::piped())
                 .stderr(Stdio::piped())
                 .pre_exec(move || {
-                    let f = File::open(ns_path.clone())?;
-                    setns(f, CloneFlags::CLONE_NEWNET)?;
+                    setns(&ns, CloneFlags::CLONE_NEWNET)?;
                     Ok(())
                 })
                 .spawn()
         }?;
         info!("spawned child process; id: {}", child.id());
+        // a child blocks once a pipe nobody reads is full, so its output goes to our log
+        if let Some(stdout) = child.stdout.take() {
+            forward_output(stdout, child.id(), Level::Info);
+        }
+        if let Some(stderr) = child.stderr.take() {
+            forward_output(stderr, child.id(), Level::Warn);
+        }
+        self.child = Some(child);
+        self.netns_name = Some(netns_name);
+        Ok(())
+    }
+
+    pub fn status(&mut self) -> String {
+        match self.child.as_mut() {
+            Some(child) => match child.try_wait() {
+                Ok(Some(status)) => format!("exited ({}); pid: {}", status, child.id()),
+                Ok(None) => format!("running; pid: {}", child.id()),
+                Err(e) => format!("unknown ({})", e),
+            },
+            None => String::from("not started"),
+        }
+    }
+
+    // kills the running child (if any) and spawns it again in the same netns
+    pub fn restart(&mut self) -> io::Result<()> {
+        let Some(netns_name) = self.netns_name.clone() else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "child has never been started",
+            ));
+        };
+        if let Some(mut child) = self.child.take() {
+            if let Ok(None) = child.try_wait() {
+                if let Err(e) = child.kill() {
+                    warn!("could not kill child process {}: {}", child.id(), e);
+                }
+            }
+            child.wait()?;
+        }
+        self.run(netns_name)?;
+        metrics().inc(CHILD_ADMIN_RESTARTS, &[]);
         Ok(())
     }
 }
+
+// logs each line the child writes until it closes the pipe (exits)
+fn forward_output<R: Read + Send + 'static>(pipe: R, pid: u32, level: Level) {
+    thread::spawn(move || {
+        for line in BufReader::new(pipe).split(b'\n') {
+            match line {
+                Ok(line) => log::log!(level, "(child {}) {}", pid, String::from_utf8_lossy(&line)),
+                Err(e) => {
+                    warn!("(child {}) stopped reading output: {}", pid, e);
+                    return;
+                }
+            }
+        }
+    });
+}
+
+// argv of a command line; quotes are dropped, not interpreted
+pub(crate) fn tokens(cmd: &str) -> Vec<String> {
+    cmd.replace('\'', "")
+        .split_whitespace()
+        .map(String::from)
+        .collect()
+}
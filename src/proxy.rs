@@ -0,0 +1,149 @@
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use dhcproto::v4::{DhcpOption, DhcpOptions, MessageType, Opcode, OptionCode};
+use log::{debug, info, warn};
+use nix::sys::socket::{setsockopt, sockopt};
+use tokio::net::UdpSocket;
+
+use crate::{
+    acl::Verdict,
+    eventlog::Event,
+    metrics::{metrics, PROXY_REPLIES},
+    packet::{decode, format_mac, DHCPMessage},
+    pxe::Boot,
+    socket::{recv_buffer_size, recv_datagram, report},
+    state::State,
+};
+
+// PXE boot server discovery
+pub const PROXY_PORT: u16 = 4011;
+
+// PXE vendor options (option 43)
+// ref: Preboot Execution Environment (PXE) Specification 2.1, section 2.4.5
+const PXE_DISCOVERY_CONTROL: u8 = 6;
+// boot the file in the offer without a boot server discovery
+const DISCOVERY_USE_BOOT_FILE: u8 = 0b1000;
+
+// a proxyDHCP answer to a PXE client: boot server and file only, no address.
+// OFFER for a DISCOVER on port 67, ACK for a REQUEST on port 4011.
+pub fn answer(
+    req: &DHCPMessage,
+    boot: &Boot,
+    server_id: Ipv4Addr,
+    msg_type: MessageType,
+) -> DHCPMessage {
+    let mut msg = req.raw();
+    msg.set_opcode(Opcode::BootReply)
+        .set_hops(0)
+        .set_yiaddr(Ipv4Addr::UNSPECIFIED)
+        .set_siaddr(Ipv4Addr::UNSPECIFIED)
+        .clear_sname();
+    let mut opts = DhcpOptions::new();
+    opts.insert(DhcpOption::MessageType(msg_type));
+    opts.insert(DhcpOption::ServerIdentifier(server_id));
+    opts.insert(DhcpOption::ClassIdentifier(b"PXEClient".to_vec()));
+    opts.insert(DhcpOption::VendorExtensions(vec![
+        PXE_DISCOVERY_CONTROL,
+        1,
+        DISCOVERY_USE_BOOT_FILE,
+        u8::from(OptionCode::End),
+    ]));
+    if let Some(guid) = req.raw().opts().get(OptionCode::ClientMachineIdentifier) {
+        opts.insert(guid.clone());
+    }
+    msg.set_opts(opts);
+    boot.apply(&mut msg);
+    DHCPMessage::from(msg)
+}
+
+// sends an answer and records it like a relayed reply
+pub async fn send(
+    sock: &UdpSocket,
+    state: &State,
+    req: &DHCPMessage,
+    msg: &DHCPMessage,
+    boot: &Boot,
+    dst: SocketAddr,
+) -> io::Result<()> {
+    let data = msg.encode_within(req.max_message_size())?;
+    sock.send_to(&data, dst).await?;
+    metrics().inc(PROXY_REPLIES, &[("type", &msg.msg_type_name())]);
+    if let Some(event_log) = &state.event_log {
+        let applied = [format!("proxy-dhcp:{}", boot.profile)];
+        event_log.write(&Event {
+            dst: Some(dst),
+            rules: &applied,
+            action: "answered",
+            ..Event::new("reply", msg, sock.local_addr()?)
+        });
+    }
+    Ok(())
+}
+
+// answers REQUESTs from PXE clients on port 4011 with the boot file for them. they are
+// checked like relayed requests: access control and forwarding on the interface.
+pub async fn serve(server_id: Ipv4Addr, state: Arc<State>) -> io::Result<()> {
+    let sock = UdpSocket::bind(format!("0.0.0.0:{}", PROXY_PORT)).await?;
+    setsockopt(&sock, sockopt::Ipv4PacketInfo, &true)?;
+    info!("proxyDHCP listening on {}", sock.local_addr()?);
+    let mut buf = vec![0; recv_buffer_size().await];
+    loop {
+        let (len, addr, arrival) = match recv_datagram(&sock, &mut buf, "request").await {
+            Ok(Some(received)) => received,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("(proxy) could not receive a request: {}", e);
+                continue;
+            }
+        };
+        let Ok(req) = decode(&buf[..len]) else {
+            debug!("(proxy) failed decode msg from {}", addr);
+            continue;
+        };
+        let req = DHCPMessage::from(req);
+        if req.msg_type() != Some(MessageType::Request) {
+            continue;
+        }
+        let vlan = arrival.and_then(|a| state.vlans.read().unwrap().get(&a.ifindex).cloned());
+        let iface = match vlan {
+            Some(v) => Some(v.name),
+            None => state.interface_for(&req, addr),
+        };
+        let event = Event {
+            interface: iface.as_deref(),
+            ..Event::new("request", &req, addr)
+        };
+        if !state.is_forwarding(iface.as_deref()) {
+            debug!("(proxy) forwarding disabled on {:?}", iface);
+            report(&state, event.dropped("disabled"));
+            continue;
+        }
+        let rules = state.rules();
+        if let Verdict::Deny(reason) = rules.acl.check(&req) {
+            info!(
+                "(proxy) drop msg from {}: {}",
+                format_mac(req.chaddr()),
+                reason
+            );
+            let applied = [format!("acl:{}", reason)];
+            let event = Event {
+                rules: &applied,
+                ..event
+            };
+            report(&state, event.dropped("acl"));
+            continue;
+        }
+        let Some(boot) = rules.pxe.select(&req) else {
+            debug!("(proxy) no boot file for request from {}", addr);
+            continue;
+        };
+        let ack = answer(&req, &boot, server_id, MessageType::Ack);
+        if let Err(e) = send(&sock, &state, &req, &ack, &boot, addr).await {
+            warn!("(proxy) could not answer {}: {}", addr, e);
+        }
+    }
+}
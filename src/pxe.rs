@@ -0,0 +1,209 @@
+use std::{fmt, net::Ipv4Addr};
+
+use dhcproto::v4::{DhcpOption, Message};
+
+use crate::{config::PxeConfig, packet::DHCPMessage};
+
+// what a PXE client is told to boot, chosen when its request passes through
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Boot {
+    // "bios", "uefi", "arm64" or "ipxe"
+    pub profile: &'static str,
+    pub file: String,
+    pub next_server: Option<Ipv4Addr>,
+    pub server_name: Option<String>,
+}
+
+impl Boot {
+    // sets siaddr, file and options 66/67 of an OFFER or ACK
+    pub fn apply(&self, msg: &mut Message) {
+        if let Some(ip) = self.next_server {
+            msg.set_siaddr(ip);
+        }
+        // the `file` field only holds 128 bytes; option 67 carries longer names (URLs)
+        if self.file.len() <= 128 {
+            msg.set_fname(self.file.as_bytes());
+        } else {
+            msg.clear_fname();
+        }
+        let opts = msg.opts_mut();
+        opts.insert(DhcpOption::BootfileName(self.file.clone().into_bytes()));
+        if let Some(name) = &self.server_name {
+            opts.insert(DhcpOption::TFTPServerName(name.clone().into_bytes()));
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Pxe {
+    config: PxeConfig,
+}
+
+impl Pxe {
+    pub fn new(config: &PxeConfig) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        let c = &self.config;
+        c.bios.is_some() || c.uefi.is_some() || c.arm64.is_some() || c.ipxe.is_some()
+    }
+
+    // None for clients that are not PXE, or whose profile has no file configured.
+    // iPXE sends "PXEClient" as well, so its user class is checked first.
+    pub fn select(&self, msg: &DHCPMessage) -> Option<Boot> {
+        let c = &self.config;
+        let (profile, file) = if msg.user_classes().iter().any(|u| *u == b"iPXE") {
+            ("ipxe", &c.ipxe)
+        } else if msg
+            .vendor_class()
+            .is_some_and(|v| v.starts_with(b"PXEClient"))
+        {
+            match msg.client_arch() {
+                None | Some(0) => ("bios", &c.bios),
+                Some(6 | 7 | 9) => ("uefi", &c.uefi),
+                Some(11) => ("arm64", &c.arm64),
+                Some(_) => return None,
+            }
+        } else {
+            return None;
+        };
+        Some(Boot {
+            profile,
+            file: file.clone()?,
+            next_server: c.next_server,
+            server_name: c
+                .server_name
+                .clone()
+                .or_else(|| c.next_server.map(|ip| ip.to_string())),
+        })
+    }
+}
+
+impl fmt::Display for Pxe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let c = &self.config;
+        write!(f, "pxe")?;
+        if let Some(ip) = c.next_server {
+            write!(f, " next-server={}", ip)?;
+        }
+        if let Some(name) = &c.server_name {
+            write!(f, " server-name={}", name)?;
+        }
+        let files = [
+            ("bios", &c.bios),
+            ("uefi", &c.uefi),
+            ("arm64", &c.arm64),
+            ("ipxe", &c.ipxe),
+        ];
+        for (profile, file) in files {
+            if let Some(file) = file {
+                write!(f, " {}={}", profile, file)?;
+            }
+        }
+        writeln!(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dhcproto::v4::{Architecture, MessageType, Opcode, OptionCode};
+
+    use super::*;
+
+    fn pxe() -> Pxe {
+        Pxe::new(&PxeConfig {
+            next_server: Some(Ipv4Addr::new(10, 0, 0, 5)),
+            server_name: None,
+            bios: Some(String::from("pxelinux.0")),
+            uefi: Some(String::from("grubx64.efi")),
+            arm64: None,
+            ipxe: Some(format!("http://10.0.0.5/{}.ipxe", "a".repeat(128))),
+        })
+    }
+
+    fn request(arch: Option<u16>, user_class: Option<&[u8]>) -> DHCPMessage {
+        let unspecified = Ipv4Addr::UNSPECIFIED;
+        let mut msg = Message::new(
+            unspecified,
+            unspecified,
+            unspecified,
+            unspecified,
+            &[2, 0, 0, 0, 0, 1],
+        );
+        let opts = msg.opts_mut();
+        opts.insert(DhcpOption::MessageType(MessageType::Discover));
+        opts.insert(DhcpOption::ClassIdentifier(
+            b"PXEClient:Arch:00000:UNDI:002001".to_vec(),
+        ));
+        if let Some(arch) = arch {
+            opts.insert(DhcpOption::ClientSystemArchitecture(Architecture::from(
+                arch,
+            )));
+        }
+        if let Some(class) = user_class {
+            opts.insert(DhcpOption::UserClass(class.to_vec()));
+        }
+        DHCPMessage::from(msg)
+    }
+
+    fn profile(msg: &DHCPMessage) -> Option<&'static str> {
+        pxe().select(msg).map(|b| b.profile)
+    }
+
+    #[test]
+    fn select_by_architecture() {
+        assert_eq!(profile(&request(None, None)), Some("bios"));
+        assert_eq!(profile(&request(Some(0), None)), Some("bios"));
+        for arch in [6, 7, 9] {
+            assert_eq!(profile(&request(Some(arch), None)), Some("uefi"));
+        }
+        // no arm64 file configured
+        assert_eq!(profile(&request(Some(11), None)), None);
+        assert_eq!(profile(&request(Some(2), None)), None);
+        // iPXE sends the architecture of the firmware it was chained from
+        assert_eq!(profile(&request(Some(7), Some(b"iPXE"))), Some("ipxe"));
+
+        let mut plain = request(None, None);
+        plain
+            .raw_mut()
+            .opts_mut()
+            .remove(OptionCode::ClassIdentifier);
+        assert_eq!(profile(&plain), None);
+        assert!(!Pxe::new(&PxeConfig::default()).is_enabled());
+    }
+
+    #[test]
+    fn apply_sets_boot_fields() {
+        let boot = pxe().select(&request(Some(7), None)).unwrap();
+        assert_eq!(boot.server_name.as_deref(), Some("10.0.0.5"));
+        let mut msg = request(Some(7), None).raw();
+        msg.set_opcode(Opcode::BootReply);
+        boot.apply(&mut msg);
+        assert_eq!(msg.siaddr(), Ipv4Addr::new(10, 0, 0, 5));
+        assert_eq!(msg.fname(), Some(&b"grubx64.efi"[..]));
+        assert_eq!(
+            msg.opts().get(OptionCode::BootfileName),
+            Some(&DhcpOption::BootfileName(b"grubx64.efi".to_vec()))
+        );
+        assert_eq!(
+            msg.opts().get(OptionCode::TFTPServerName),
+            Some(&DhcpOption::TFTPServerName(b"10.0.0.5".to_vec()))
+        );
+
+        // longer than the `file` field: option 67 only
+        let boot = pxe().select(&request(None, Some(b"iPXE"))).unwrap();
+        msg.set_fname_str("stale");
+        boot.apply(&mut msg);
+        assert_eq!(msg.fname(), None);
+        assert_eq!(
+            msg.opts().get(OptionCode::BootfileName),
+            Some(&DhcpOption::BootfileName(boot.file.into_bytes()))
+        );
+    }
+}
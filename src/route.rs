@@ -1,5 +1,6 @@
 use std::{
     collections::HashMap,
+    fmt,
     fs::File,
     io::{self, BufRead, BufReader},
     net::Ipv4Addr,
@@ -7,28 +8,30 @@ use std::{
     path::Path,
 };
 
+use dhcproto::v4::{DhcpOption, DhcpOptions, Message, OptionCode, UnknownOption};
+use ipnet::Ipv4Net;
+use log::warn;
+
 const RTF_UP: u32 = 0x0001;
 const RTF_GATEWAY: u32 = 0x0002;
-pub const SEG_1: u64 = 0xFF000000;
-pub const SEG_2: u64 = 0x00FF0000;
-pub const SEG_3: u64 = 0x0000FF00;
-pub const SEG_4: u64 = 0x000000FF;
+// Microsoft's pre-RFC 3442 code for classless static routes, same format as 121
+pub(crate) const MS_CLASSLESS_STATIC_ROUTE: u8 = 249;
 
 // Export from /proc/net/route defines
 // ref: https://github.com/torvalds/linux/blob/v6.6/net/ipv4/fib_trie.c#L2976-L3024
 #[derive(Debug, Clone)]
 pub struct Route {
-    iface: String,    // %s
-    destination: u64, // %08X
-    gateway: u64,     // %08X
-    flags: u32,       // %04X
-    ref_cnt: i32,     // %d
-    use_field: u32,   // %u
-    metric: i32,      // %d
-    mask: u64,        // %08X
-    mtu: i32,         // %d
-    window: u32,      // %u
-    irtt: u32,        // %u
+    iface: String,         // %s
+    destination: Ipv4Addr, // %08X
+    gateway: Ipv4Addr,     // %08X
+    flags: u32,            // %04X
+    ref_cnt: i32,          // %d
+    use_field: u32,        // %u
+    metric: i32,           // %d
+    mask: Ipv4Addr,        // %08X
+    mtu: i32,              // %d
+    window: u32,           // %u
+    irtt: u32,             // %u
 }
 
 impl Route {
@@ -44,24 +47,41 @@ impl Route {
         let output: Vec<_> = lines
             .skip(1)
             .filter_map(|v| v.ok())
-            .map(|v| v.trim().split("\t").map(str::to_owned).collect())
-            .filter_map(|v| Route::vec_to_route(v).ok())
+            .filter(|v| !v.trim().is_empty())
+            .map(|v| v.trim().split('\t').map(str::to_owned).collect::<Vec<_>>())
+            .filter_map(|v| match Route::vec_to_route(&v) {
+                Ok(r) => Some(r),
+                Err(e) => {
+                    // e.g. a file caught half-written
+                    warn!("skipping route line {:?}: {}", v.join("\t"), e);
+                    None
+                }
+            })
             .collect();
         Ok(output)
     }
 
-    fn vec_to_route(v: Vec<String>) -> Result<Route, ParseIntError> {
-        let iface: String = v[0].to_owned();
-        let destination: u64 = u64::from_str_radix(&v[1], 16)?;
-        let gateway: u64 = u64::from_str_radix(&v[2], 16)?;
-        let flags: u32 = u32::from_str_radix(&v[3], 16)?;
-        let ref_cnt: i32 = v[4].parse()?;
-        let use_field: u32 = v[5].parse()?;
-        let metric: i32 = v[6].parse()?;
-        let mask: u64 = u64::from_str_radix(&v[7], 16)?;
-        let mtu: i32 = v[8].parse()?;
-        let window: u32 = v[9].parse()?;
-        let irtt: u32 = v[10].parse()?;
+    fn vec_to_route(v: &[String]) -> io::Result<Route> {
+        let field = |i: usize| {
+            v.get(i).map(String::as_str).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} fields, expected 11", v.len()),
+                )
+            })
+        };
+        let number = |e: ParseIntError| io::Error::new(io::ErrorKind::InvalidData, e);
+        let iface: String = field(0)?.to_owned();
+        let destination = parse_addr(field(1)?).map_err(number)?;
+        let gateway = parse_addr(field(2)?).map_err(number)?;
+        let flags: u32 = u32::from_str_radix(field(3)?, 16).map_err(number)?;
+        let ref_cnt: i32 = field(4)?.parse().map_err(number)?;
+        let use_field: u32 = field(5)?.parse().map_err(number)?;
+        let metric: i32 = field(6)?.parse().map_err(number)?;
+        let mask = parse_addr(field(7)?).map_err(number)?;
+        let mtu: i32 = field(8)?.parse().map_err(number)?;
+        let window: u32 = field(9)?.parse().map_err(number)?;
+        let irtt: u32 = field(10)?.parse().map_err(number)?;
         let r = Route {
             iface,
             destination,
@@ -79,79 +99,448 @@ impl Route {
     }
 
     pub fn parse_network(&self, map: &mut HashMap<String, RouteInfo>) -> io::Result<()> {
-        if self.flags & RTF_UP != 0 {
-            if self.gateway != 0 && (self.flags & RTF_GATEWAY) != 0 {
-                let mut route_info = if let Some(v) = map.get(&self.iface) {
-                    *v
-                } else {
-                    let v = RouteInfo::default();
-                    map.insert(self.iface.clone(), v);
-                    v
-                };
-                route_info.gateway = Ipv4Addr::new(
-                    (self.gateway & SEG_4) as u8,
-                    ((self.gateway & SEG_3) >> 8) as u8,
-                    ((self.gateway & SEG_2) >> 16) as u8,
-                    ((self.gateway & SEG_1) >> 24) as u8,
-                );
-                map.insert(self.iface.clone(), route_info);
-            } else {
-                let mut mask = self.mask;
-                let mut segment: u8 = 0;
-                while mask != 0 {
-                    segment += (mask & 1) as u8;
-                    mask >>= 1;
-                }
-                if (self.destination >> segment) == 0 {
-                    let mut route_info = if let Some(v) = map.get(&self.iface) {
-                        *v
-                    } else {
-                        let v = RouteInfo::default();
-                        map.insert(self.iface.clone(), v);
-                        v
-                    };
-                    route_info.destination = Ipv4Addr::new(
-                        (self.destination & SEG_4) as u8,
-                        ((self.destination & SEG_3) >> 8) as u8,
-                        ((self.destination & SEG_2) >> 16) as u8,
-                        ((self.destination & SEG_1) >> 24) as u8,
-                    );
-                    route_info.mask = Ipv4Addr::new(
-                        (self.mask & SEG_4) as u8,
-                        ((self.mask & SEG_3) >> 8) as u8,
-                        ((self.mask & SEG_2) >> 16) as u8,
-                        ((self.mask & SEG_1) >> 24) as u8,
-                    );
-                    map.insert(self.iface.clone(), route_info);
-                }
-            }
+        if self.flags & RTF_UP == 0 {
+            return Ok(());
         }
+        // one bad line must not keep the rest of the table (and every later sync) from
+        // being read
+        let Ok(destination) = Ipv4Net::with_netmask(self.destination, self.mask) else {
+            warn!(
+                "skipping route on {}: non-contiguous mask {} for {}",
+                self.iface, self.mask, self.destination
+            );
+            return Ok(());
+        };
+        // a route has no host bits set in its destination
+        if destination.trunc() != destination {
+            return Ok(());
+        }
+        let via_gateway = !self.gateway.is_unspecified() && (self.flags & RTF_GATEWAY) != 0;
+        let entry = RouteEntry {
+            destination,
+            gateway: via_gateway.then_some(self.gateway),
+            metric: self.metric,
+            mtu: self.mtu,
+            window: self.window,
+            ref_cnt: self.ref_cnt,
+            use_field: self.use_field,
+            irtt: self.irtt,
+        };
+        let info = map.entry(self.iface.clone()).or_default();
+        // only the preferred (lowest metric) default route is kept
+        let preferred = info.default.map_or(i32::MAX, |d| d.metric) > entry.metric;
+        match entry.gateway {
+            Some(_) if destination.prefix_len() == 0 && preferred => info.default = Some(entry),
+            Some(_) if destination.prefix_len() == 0 => {}
+            Some(_) => info.routes.push(entry),
+            None if destination.prefix_len() != 0 => info.subnets.push(entry),
+            None => {}
+        }
+        info.subnets.sort_by_key(|e| e.metric);
+        info.routes.sort_by_key(|e| e.metric);
         Ok(())
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+// /proc/net/route prints each address as the u32 it is in memory (network order),
+// so its bytes come back in host order
+fn parse_addr(s: &str) -> Result<Ipv4Addr, ParseIntError> {
+    u32::from_str_radix(s, 16).map(|v| Ipv4Addr::from(v.to_ne_bytes()))
+}
+
+// one line of the route table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RouteEntry {
+    pub destination: Ipv4Net,
+    // None for on-link (connected) routes
+    pub gateway: Option<Ipv4Addr>,
+    pub metric: i32,
+    pub mtu: i32,
+    pub window: u32,
+    pub ref_cnt: i32,
+    pub use_field: u32,
+    pub irtt: u32,
+}
+
+impl fmt::Display for RouteEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.destination)?;
+        if let Some(gateway) = self.gateway {
+            write!(f, " via {}", gateway)?;
+        }
+        write!(f, " metric {}", self.metric)?;
+        if self.mtu != 0 {
+            write!(f, " mtu {}", self.mtu)?;
+        }
+        Ok(())
+    }
+}
+
+// routes of one interface; each list is ordered by metric (preferred first)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct RouteInfo {
-    pub destination: Ipv4Addr,
-    pub gateway: Ipv4Addr,
-    pub mask: Ipv4Addr,
+    // connected subnets
+    pub subnets: Vec<RouteEntry>,
+    // the default route with the lowest metric
+    pub default: Option<RouteEntry>,
+    // other routes via a gateway
+    pub routes: Vec<RouteEntry>,
 }
 
 impl RouteInfo {
     pub fn is_full(&self) -> bool {
-        !(self.destination.is_unspecified()
-            || self.gateway.is_unspecified()
-            || self.mask.is_unspecified())
+        self.subnet().is_some() && self.gateway().is_some()
+    }
+
+    // the preferred connected subnet
+    pub fn subnet(&self) -> Option<Ipv4Net> {
+        self.subnets.first().map(|e| e.destination)
+    }
+
+    pub fn gateway(&self) -> Option<Ipv4Addr> {
+        self.default.and_then(|e| e.gateway)
+    }
+
+    // the smallest MTU set on the interface's routes (0 in the table when unset)
+    pub fn mtu(&self) -> Option<u32> {
+        self.subnets
+            .iter()
+            .chain(&self.default)
+            .chain(&self.routes)
+            .filter_map(|e| u32::try_from(e.mtu).ok())
+            .filter(|mtu| *mtu > 0)
+            .min()
+    }
+
+    // the most specific connected subnet containing `ip`, by metric among equals
+    pub fn subnet_for(&self, ip: Ipv4Addr) -> Option<Ipv4Net> {
+        self.subnets
+            .iter()
+            .filter(|e| e.destination.contains(&ip))
+            .max_by_key(|e| (e.destination.prefix_len(), -i64::from(e.metric)))
+            .map(|e| e.destination)
+    }
+
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        self.subnet_for(ip).is_some()
+    }
+
+    // options 1, 3, 121 and 249 for a client on this interface (its subnet is the one
+    // holding yiaddr; option 1 is left alone when no known subnet does). options the server
+    // sent are kept unless `overwrite`. returns whether anything was set.
+    // 121 replaces 3 on clients that support it, so it carries the default route as well and
+    // is only added along with 3; otherwise it would hide the server's router.
+    // ref: https://www.rfc-editor.org/rfc/rfc3442#page-5
+    pub fn apply(&self, msg: &mut Message, overwrite: bool) -> bool {
+        let subnet = self.subnet_for(msg.yiaddr());
+        let opts = msg.opts_mut();
+        let missing = |opts: &DhcpOptions, code: u8| opts.get(OptionCode::from(code)).is_none();
+        let mut applied = false;
+        if let Some(subnet) = subnet {
+            if overwrite || missing(opts, 1) {
+                opts.insert(DhcpOption::SubnetMask(subnet.netmask()));
+                applied = true;
+            }
+        }
+        let Some(gateway) = self.gateway() else {
+            return applied;
+        };
+        if !overwrite && !missing(opts, 3) {
+            return applied;
+        }
+        opts.insert(DhcpOption::Router(vec![gateway]));
+        let mut routes: Vec<_> = self
+            .routes
+            .iter()
+            .filter_map(|e| Some((e.destination, e.gateway?)))
+            .collect();
+        routes.push((Ipv4Net::default(), gateway));
+        if overwrite || missing(opts, MS_CLASSLESS_STATIC_ROUTE) {
+            opts.insert(DhcpOption::Unknown(UnknownOption::new(
+                OptionCode::from(MS_CLASSLESS_STATIC_ROUTE),
+                classless_routes(&routes),
+            )));
+        }
+        if overwrite || missing(opts, 121) {
+            opts.insert(DhcpOption::ClasslessStaticRoute(routes));
+        }
+        true
+    }
+}
+
+impl fmt::Display for RouteInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let entries = self.subnets.iter().chain(&self.default).chain(&self.routes);
+        let entries: Vec<_> = entries.map(|e| e.to_string()).collect();
+        write!(f, "{}", entries.join(", "))
+    }
+}
+
+// <prefix len> <significant octets of the destination> <router>, per route
+pub(crate) fn classless_routes(routes: &[(Ipv4Net, Ipv4Addr)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (net, router) in routes {
+        let significant = usize::from(net.prefix_len()).div_ceil(8);
+        out.push(net.prefix_len());
+        out.extend(&net.network().octets()[..significant]);
+        out.extend(router.octets());
     }
+    out
 }
 
-impl Default for RouteInfo {
-    fn default() -> Self {
-        let default_v4 = Ipv4Addr::new(0, 0, 0, 0);
-        Self {
-            destination: default_v4,
-            gateway: default_v4,
-            mask: default_v4,
+// the routes `classless_routes` encodes; None when `data` is not a list of them
+pub(crate) fn parse_classless_routes(data: &[u8]) -> Option<Vec<(Ipv4Net, Ipv4Addr)>> {
+    let mut routes = Vec::new();
+    let mut rest = data;
+    while let Some((&prefix_len, tail)) = rest.split_first() {
+        let significant = usize::from(prefix_len).div_ceil(8);
+        if prefix_len > 32 || tail.len() < significant + 4 {
+            return None;
         }
+        let mut destination = [0; 4];
+        destination[..significant].copy_from_slice(&tail[..significant]);
+        let net = Ipv4Net::new(Ipv4Addr::from(destination), prefix_len).ok()?;
+        let router = <[u8; 4]>::try_from(&tail[significant..significant + 4]).ok()?;
+        routes.push((net, Ipv4Addr::from(router)));
+        rest = &tail[significant + 4..];
+    }
+    Some(routes)
+}
+
+#[cfg(test)]
+mod tests {
+    use dhcproto::v4::{MessageType, Opcode};
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn route(iface: &str, destination: [u8; 4], gateway: [u8; 4], mask: u32, metric: i32) -> Route {
+        let gateway = Ipv4Addr::from(gateway);
+        let flags = if gateway.is_unspecified() {
+            RTF_UP
+        } else {
+            RTF_UP | RTF_GATEWAY
+        };
+        Route {
+            iface: iface.to_string(),
+            destination: Ipv4Addr::from(destination),
+            gateway,
+            flags,
+            ref_cnt: 0,
+            use_field: 0,
+            metric,
+            mask: Ipv4Addr::from(mask),
+            mtu: 0,
+            window: 0,
+            irtt: 0,
+        }
+    }
+
+    // a mask is contiguous when its inverse is all ones from some bit down
+    fn is_contiguous(mask: u32) -> bool {
+        let host = !mask;
+        host & host.wrapping_add(1) == 0
+    }
+
+    fn prefix_mask(len: u8) -> u32 {
+        u32::MAX.checked_shl(32 - u32::from(len)).unwrap_or(0)
+    }
+
+    fn mask() -> impl Strategy<Value = u32> {
+        prop_oneof![(0u8..=32).prop_map(prefix_mask), any::<u32>()]
+    }
+
+    proptest! {
+        #[test]
+        fn with_netmask_accepts_contiguous_masks_only(destination: u32, mask in mask()) {
+            let net = Ipv4Net::with_netmask(Ipv4Addr::from(destination), Ipv4Addr::from(mask));
+            prop_assert_eq!(net.is_ok(), is_contiguous(mask));
+            if let Ok(net) = net {
+                prop_assert_eq!(u32::from(net.netmask()), mask);
+                prop_assert_eq!(u32::from(net.prefix_len()), mask.leading_ones());
+            }
+        }
+
+        #[test]
+        fn parse_network_skips_non_contiguous_masks(destination: u32, mask in mask()) {
+            let destination = destination & mask;
+            let r = route("eth0", destination.to_be_bytes(), [0; 4], mask, 0);
+            let mut map = HashMap::new();
+            prop_assert!(r.parse_network(&mut map).is_ok());
+            let subnets = map.get("eth0").map_or(0, |i| i.subnets.len());
+            prop_assert_eq!(subnets, usize::from(is_contiguous(mask) && mask != 0));
+        }
+    }
+
+    fn parse(routes: &[Route]) -> HashMap<String, RouteInfo> {
+        let mut map = HashMap::new();
+        for r in routes {
+            r.parse_network(&mut map).unwrap();
+        }
+        map
+    }
+
+    #[test]
+    fn read_route_skips_short_lines() {
+        let path = std::env::temp_dir().join(format!("middle-sock-route-{}", std::process::id()));
+        let table =
+            "Iface\tDestination\tGateway\tFlags\tRefCnt\tUse\tMetric\tMask\tMTU\tWindow\tIRTT\n\
+            eth0\t00000000\t0100000A\t0003\t0\t0\t100\t00000000\t0\t0\t0\n\
+            eth0\t0000000A\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0\n\
+            \n\
+            eth1\t0000A8C0\t00000000\t00";
+        std::fs::write(&path, table).unwrap();
+        let routes = Route::new(&path);
+        std::fs::remove_file(&path).unwrap();
+        let routes = routes.unwrap();
+        assert_eq!(routes.len(), 2);
+        assert!(routes.iter().all(|r| r.iface == "eth0"));
+        assert!(Route::vec_to_route(&[String::from("eth0")]).is_err());
+    }
+
+    #[test]
+    fn parse_network_sorts_entries() {
+        let map = parse(&[
+            route("eth0", [0; 4], [10, 0, 0, 254], 0, 200),
+            route("eth0", [0; 4], [10, 0, 0, 1], 0, 100),
+            route("eth0", [10, 0, 0, 0], [0; 4], prefix_mask(24), 100),
+            route("eth0", [10, 1, 0, 0], [10, 0, 0, 2], prefix_mask(16), 100),
+            route("eth0", [172, 16, 0, 0], [0; 4], prefix_mask(16), 50),
+            // host bits set in the destination
+            route("eth0", [10, 2, 0, 1], [0; 4], prefix_mask(16), 100),
+            route("eth1", [192, 168, 0, 0], [0; 4], prefix_mask(24), 0),
+        ]);
+        let eth0 = &map["eth0"];
+        assert_eq!(eth0.gateway(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(eth0.subnet(), Some("172.16.0.0/16".parse().unwrap()));
+        assert_eq!(
+            eth0.subnet_for(Ipv4Addr::new(10, 0, 0, 50)),
+            Some("10.0.0.0/24".parse().unwrap())
+        );
+        assert_eq!(eth0.subnets.len(), 2);
+        assert_eq!(eth0.routes.len(), 1);
+        assert!(eth0.is_full());
+        assert!(!eth0.contains(Ipv4Addr::new(10, 2, 0, 50)));
+        assert!(!map["eth1"].is_full());
+    }
+
+    fn info() -> RouteInfo {
+        parse(&[
+            route("eth0", [0; 4], [10, 0, 0, 1], 0, 100),
+            route("eth0", [10, 0, 0, 0], [0; 4], prefix_mask(24), 100),
+            route("eth0", [10, 1, 0, 0], [10, 0, 0, 2], prefix_mask(16), 100),
+        ])
+        .remove("eth0")
+        .unwrap()
+    }
+
+    fn ack(yiaddr: [u8; 4]) -> Message {
+        let mut msg = Message::default();
+        msg.set_opcode(Opcode::BootReply)
+            .set_yiaddr(Ipv4Addr::from(yiaddr));
+        msg.opts_mut()
+            .insert(DhcpOption::MessageType(MessageType::Ack));
+        msg
+    }
+
+    #[test]
+    fn apply_fills_missing_options() {
+        let mut msg = ack([10, 0, 0, 50]);
+        assert!(info().apply(&mut msg, false));
+        let opts = msg.opts();
+        assert_eq!(
+            opts.get(OptionCode::SubnetMask),
+            Some(&DhcpOption::SubnetMask(Ipv4Addr::new(255, 255, 255, 0)))
+        );
+        assert_eq!(
+            opts.get(OptionCode::Router),
+            Some(&DhcpOption::Router(vec![Ipv4Addr::new(10, 0, 0, 1)]))
+        );
+        let routes = vec![
+            ("10.1.0.0/16".parse().unwrap(), Ipv4Addr::new(10, 0, 0, 2)),
+            (Ipv4Net::default(), Ipv4Addr::new(10, 0, 0, 1)),
+        ];
+        assert_eq!(
+            opts.get(OptionCode::ClasslessStaticRoute),
+            Some(&DhcpOption::ClasslessStaticRoute(routes))
+        );
+        let Some(DhcpOption::Unknown(ms)) = opts.get(OptionCode::from(MS_CLASSLESS_STATIC_ROUTE))
+        else {
+            panic!("option 249 missing");
+        };
+        assert_eq!(ms.data(), &[16, 10, 1, 10, 0, 0, 2, 0, 10, 0, 0, 1]);
+    }
+
+    #[test]
+    fn apply_keeps_the_servers_router_and_mask() {
+        let mut msg = ack([10, 0, 0, 50]);
+        let router = DhcpOption::Router(vec![Ipv4Addr::new(10, 0, 0, 254)]);
+        let mask = DhcpOption::SubnetMask(Ipv4Addr::new(255, 255, 0, 0));
+        msg.opts_mut().insert(router.clone());
+        msg.opts_mut().insert(mask.clone());
+        assert!(!info().apply(&mut msg, false));
+        let opts = msg.opts();
+        assert_eq!(opts.get(OptionCode::Router), Some(&router));
+        assert_eq!(opts.get(OptionCode::SubnetMask), Some(&mask));
+        // 121 would hide the server's router from clients that support it
+        assert!(opts.get(OptionCode::ClasslessStaticRoute).is_none());
+
+        assert!(info().apply(&mut msg, true));
+        assert_ne!(msg.opts().get(OptionCode::Router), Some(&router));
+        assert!(msg.opts().get(OptionCode::ClasslessStaticRoute).is_some());
+    }
+
+    #[test]
+    fn apply_leaves_the_mask_alone_outside_known_subnets() {
+        let mut msg = ack([172, 16, 0, 50]);
+        assert!(info().apply(&mut msg, false));
+        assert!(msg.opts().get(OptionCode::SubnetMask).is_none());
+        assert!(msg.opts().get(OptionCode::Router).is_some());
+    }
+
+    #[test]
+    fn apply_uses_the_subnet_holding_yiaddr() {
+        let mut info = info();
+        info.subnets.extend(
+            parse(&[route("eth0", [10, 5, 0, 0], [0; 4], prefix_mask(16), 200)])["eth0"]
+                .subnets
+                .clone(),
+        );
+        let mut msg = ack([10, 5, 0, 9]);
+        assert!(info.apply(&mut msg, false));
+        assert_eq!(
+            msg.opts().get(OptionCode::SubnetMask),
+            Some(&DhcpOption::SubnetMask(Ipv4Addr::new(255, 255, 0, 0)))
+        );
+    }
+
+    #[test]
+    fn classless_routes_encode_significant_octets() {
+        let routes = [
+            ("10.0.0.0/8".parse().unwrap(), Ipv4Addr::new(10, 0, 0, 1)),
+            (
+                "192.168.1.128/25".parse().unwrap(),
+                Ipv4Addr::new(10, 0, 0, 2),
+            ),
+            (Ipv4Net::default(), Ipv4Addr::new(10, 0, 0, 1)),
+        ];
+        assert_eq!(
+            classless_routes(&routes),
+            [
+                8, 10, 10, 0, 0, 1, //
+                25, 192, 168, 1, 128, 10, 0, 0, 2, //
+                0, 10, 0, 0, 1,
+            ]
+        );
+        assert_eq!(
+            parse_classless_routes(&classless_routes(&routes)).as_deref(),
+            Some(&routes[..])
+        );
+        assert_eq!(parse_classless_routes(&[]), Some(Vec::new()));
+        assert_eq!(parse_classless_routes(&[24, 10, 0, 0, 10, 0, 0]), None);
+        assert_eq!(
+            parse_classless_routes(&[33, 10, 0, 0, 1, 10, 0, 0, 1]),
+            None
+        );
     }
 }
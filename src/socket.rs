@@ -1,53 +1,155 @@
-use std::{io, net::SocketAddr, path::Path, sync::Arc};
+use std::{
+    collections::HashMap,
+    io::{self, IoSlice, IoSliceMut},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    os::fd::AsRawFd,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use dhcproto::{
-    v4::{Message, CLIENT_PORT, SERVER_PORT},
-    Decodable, Decoder, Encodable, Encoder,
+    v4::{MessageType, CLIENT_PORT, SERVER_PORT},
+    Encodable, Encoder,
 };
 
 use log::{debug, info, warn};
+use nix::{
+    cmsg_space, libc,
+    sys::socket::{
+        recvmsg, sendmsg, setsockopt, sockopt, ControlMessage, ControlMessageOwned, MsgFlags,
+        SockaddrIn,
+    },
+};
 use tokio::{
+    io::Interest,
     net::{UdpSocket, UnixStream},
     sync::mpsc,
 };
+use tracing::{info_span, Instrument, Span};
+
+use crate::{
+    acl::{Class, Verdict},
+    capture::{Direction, Record},
+    config::MalformedPolicy,
+    eventlog::Event,
+    metrics::{
+        metrics, CHANNEL_DEPTH, DECODE_FAILURES, PACKETS_DROPPED, PACKETS_FORWARDED,
+        PACKETS_RECEIVED, PASSTHROUGH_FORWARDED, REPLY_LATENCY, UPSTREAM_SEND_ERRORS,
+    },
+    network::largest_mtu,
+    packet::{
+        buffer_size, check_bootp, decode, fill_giaddr, format_mac, hex_dump, is_plain_bootp,
+        peek_header, DHCPMessage, BOOTP, MAX_DATAGRAM, UNDECODABLE,
+    },
+    proxy,
+    pxe::Boot,
+    state::State,
+    vlan::{self, Vlan},
+};
 
-use crate::packet::DHCPMessage;
+// requests without a reply after this long are forgotten
+const PENDING_TIMEOUT: Duration = Duration::from_secs(60);
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+// BOOTP requests relayed this many times are dropped
+// ref: https://www.rfc-editor.org/rfc/rfc1542#section-4.1.1
+const MAX_HOPS: u8 = 16;
+
+#[derive(Debug, Clone)]
+struct Pending {
+    client: SocketAddr,
+    upstream: SocketAddr,
+    iface: Option<String>,
+    sent: Instant,
+    // largest reply the client accepts (option 57)
+    max_size: usize,
+    boot: Option<Boot>,
+    // the VLAN the request came in on
+    vlan: Option<Vlan>,
+}
+
+// a decoded request on its way from the receiver to the sender task
+#[derive(Debug)]
+struct Relayed {
+    msg: DHCPMessage,
+    client: SocketAddr,
+    iface: Option<String>,
+    class: Option<Class>,
+    // BOOTP requests are forwarded as received (apart from hops), since re-encoding
+    // would replace a vend field that is not RFC 1048 options
+    bootp: Option<Vec<u8>>,
+    boot: Option<Boot>,
+    // proxyDHCP OFFER for a PXE client's DISCOVER
+    offer: Option<DHCPMessage>,
+    vlan: Option<Vlan>,
+    // transformations done by the receiver, for the event log
+    applied: Vec<String>,
+    // root span of the transaction
+    span: Span,
+}
+
+// an undecodable request passed through, with the relay agent fields updated
+#[derive(Debug)]
+struct Opaque {
+    data: Vec<u8>,
+    client: SocketAddr,
+    iface: Option<String>,
+    vlan: Option<Vlan>,
+    span: Span,
+}
+
+#[derive(Debug)]
+enum Forward {
+    Message(Box<Relayed>),
+    Opaque(Opaque),
+}
 
 #[derive(Debug)]
 pub struct Socket {
     receiver: Arc<UdpSocket>,
     sender: Arc<UdpSocket>,
     domain: Option<Arc<UnixStream>>,
+    state: Arc<State>,
 }
 
 impl Socket {
     pub async fn new<P: AsRef<Path>>(fp: P) -> io::Result<Self> {
-        let receiver_sock = UdpSocket::bind(format!("0.0.0.0:{}", SERVER_PORT)).await?;
+        let receiver_sock = bind_server_port().await?;
         let sender_sock = UdpSocket::bind(format!("0.0.0.0:{}", CLIENT_PORT)).await?;
         let domain_sock = UnixStream::connect(fp).await?;
         Ok(Self {
             receiver: Arc::new(receiver_sock),
             sender: Arc::new(sender_sock),
             domain: Some(Arc::new(domain_sock)),
+            state: Arc::new(State::default()),
         })
     }
 
     pub async fn new_without_domain() -> io::Result<Self> {
-        let receiver_sock = UdpSocket::bind(format!("0.0.0.0:{}", SERVER_PORT)).await?;
+        let receiver_sock = bind_server_port().await?;
         let sender_sock = UdpSocket::bind(format!("0.0.0.0:{}", CLIENT_PORT)).await?;
         Ok(Self {
             receiver: Arc::new(receiver_sock),
             sender: Arc::new(sender_sock),
             domain: None,
+            state: Arc::new(State::default()),
         })
     }
 
+    pub fn set_state(&mut self, state: Arc<State>) {
+        self.state = state;
+    }
+
     pub async fn listen(&self, server_host: SocketAddr) -> io::Result<()> {
         let runtime_ip = String::from("172.17.0.1");
-        let (tx, mut rx) = mpsc::channel::<(DHCPMessage, SocketAddr)>(1024);
+        let (tx, mut rx) = mpsc::channel::<Forward>(1024);
         debug!("server_host: {}", &server_host);
         let receiver_sock = Arc::clone(&self.receiver);
         let sender_sock = Arc::clone(&self.sender);
+        let server_local = self.receiver.local_addr()?;
+        let client_local = self.sender.local_addr()?;
+        let pending = Arc::new(Mutex::new(HashMap::<u32, Pending>::new()));
         if let Some(s) = &self.domain {
             let _domain_sock = Arc::clone(s);
             tokio::spawn(async move {
@@ -55,39 +157,863 @@ impl Socket {
                 // sender process w/ unix domain sock
             });
         } else {
+            let pending = Arc::clone(&pending);
+            let reply_sock = Arc::clone(&self.sender);
+            let client_sock = Arc::clone(&self.receiver);
+            let reply_pending = Arc::clone(&pending);
+            let reply_state = Arc::clone(&self.state);
+            tokio::spawn(async move {
+                info!("spawning reply receiver (udp)");
+                if let Err(e) =
+                    Socket::relay_replies(reply_sock, client_sock, reply_pending, reply_state).await
+                {
+                    warn!("reply receiver stopped: {}", e);
+                }
+            });
+            let state = Arc::clone(&self.state);
+            let proxy_sock = Arc::clone(&self.receiver);
             tokio::spawn(async move {
                 info!("spawning sender (udp)");
                 // sender process w/ udp
-                while let Some((msg, addr)) = rx.recv().await {
-                    debug!("(sender task) msg: {:?}, addr: {:?}", msg, addr);
-                    if addr.ip().to_string() == runtime_ip {
-                        let mut buf = Vec::new();
-                        let mut e = Encoder::new(&mut buf);
-                        let _ = msg.raw().encode(&mut e);
-                        info!("send to host...");
-                        if let Err(_) = sender_sock.send_to(&buf, server_host).await {
-                            warn!("could not send to server_host")
+                while let Some(forward) = rx.recv().await {
+                    metrics().add(CHANNEL_DEPTH, &[], -1.0);
+                    let relayed = match forward {
+                        Forward::Message(relayed) => *relayed,
+                        Forward::Opaque(opaque) => {
+                            forward_opaque(
+                                &sender_sock,
+                                opaque,
+                                server_host,
+                                &runtime_ip,
+                                &state,
+                                &pending,
+                            )
+                            .await;
+                            continue;
+                        }
+                    };
+                    let Relayed {
+                        msg,
+                        client: addr,
+                        iface,
+                        class,
+                        bootp,
+                        boot,
+                        offer,
+                        vlan,
+                        mut applied,
+                        span,
+                    } = relayed;
+                    debug!(
+                        "(sender task) msg: {:?}, addr: {:?}, class: {:?}",
+                        msg, addr, class
+                    );
+                    if let Some(c) = &class {
+                        applied.push(format!("class:{}", c.name));
+                        if let Some(upstream) = c.upstream {
+                            applied.push(format!("upstream:{}", upstream));
                         }
-                    } else {
+                    }
+                    let event = Event {
+                        interface: iface.as_deref(),
+                        rules: &applied,
+                        ..Event::new("request", &msg, addr)
+                    };
+                    // clients on a VLAN reach us directly, not through the runtime
+                    if vlan.is_none() && addr.ip().to_string() != runtime_ip {
                         info!("addr is not from runtime?");
+                        report(&state, event.dropped("not_runtime"));
+                        continue;
+                    }
+                    // the upstream server still gets the DISCOVER and offers the address
+                    if let (Some(offer), Some(boot)) = (&offer, &boot) {
+                        if let Err(e) =
+                            proxy::send(&proxy_sock, &state, &msg, offer, boot, addr).await
+                        {
+                            warn!("(proxy) could not offer to {}: {}", addr, e);
+                        }
                     }
+                    let upstream = class
+                        .as_ref()
+                        .and_then(|c| c.upstream)
+                        .or(vlan.as_ref().and_then(|v| v.upstream))
+                        .unwrap_or(server_host);
+                    let forward = info_span!(parent: &span, "forward", upstream = %upstream);
+                    let buf = bootp.unwrap_or_else(|| {
+                        let mut buf = Vec::new();
+                        forward.in_scope(|| {
+                            let mut e = Encoder::new(&mut buf);
+                            let _ = msg.raw().encode(&mut e);
+                        });
+                        buf
+                    });
+                    if let Some(capture) = &state.capture {
+                        capture.write(
+                            &msg,
+                            &Record {
+                                direction: Direction::Outbound,
+                                form: "transformed",
+                                iface: iface.as_deref(),
+                                src: client_local,
+                                dst: upstream,
+                                data: &buf,
+                            },
+                        );
+                    }
+                    info!("send to host...");
+                    let event = Event {
+                        dst: Some(upstream),
+                        ..event
+                    };
+                    let xid = msg.xid();
+                    expect_reply(
+                        &pending,
+                        xid,
+                        Pending {
+                            client: addr,
+                            upstream,
+                            iface: iface.clone(),
+                            sent: Instant::now(),
+                            max_size: msg.max_message_size(),
+                            boot,
+                            vlan,
+                        },
+                    );
+                    if sender_sock
+                        .send_to(&buf, upstream)
+                        .instrument(forward)
+                        .await
+                        .is_err()
+                    {
+                        pending.lock().unwrap().remove(&xid);
+                        warn!("could not send to {}", upstream);
+                        metrics().inc(UPSTREAM_SEND_ERRORS, &[("upstream", &upstream.to_string())]);
+                        report(&state, event.dropped("send_error"));
+                        continue;
+                    }
+                    report(&state, event);
                 }
             });
         }
+        let proxy_id = self.state.config.read().unwrap().proxy_dhcp.server_id;
+        let passed = [String::from(UNDECODABLE)];
         info!("spawning receiver");
-        let mut buf = [0; 1024];
+        let mut buf = vec![0; recv_buffer_size().await];
         loop {
-            let (len, addr) = receiver_sock.recv_from(&mut buf).await?;
-            let msg = Message::decode(&mut Decoder::new(&buf[..len]));
-            if let Ok(msg) = msg {
-                info!("DHCP Message received!");
-                debug!("msg: {:?}", msg);
-                if let Err(_) = tx.send((msg.into(), addr)).await {
+            let (len, addr, arrival) =
+                match recv_datagram(&receiver_sock, &mut buf, "request").await {
+                    Ok(Some(received)) => received,
+                    Ok(None) => continue,
+                    // e.g. ENOBUFS; the socket is still usable
+                    Err(e) => {
+                        warn!("could not receive a request: {}", e);
+                        continue;
+                    }
+                };
+            // a server answering a request with giaddr set sends to the relay's server port
+            if buf[..len].first() == Some(&BOOTREPLY) {
+                relay_reply(
+                    &buf[..len],
+                    addr,
+                    server_local,
+                    server_local,
+                    &self.receiver,
+                    &pending,
+                    &self.state,
+                )
+                .await;
+                continue;
+            }
+            let vlan =
+                arrival.and_then(|a| self.state.vlans.read().unwrap().get(&a.ifindex).cloned());
+            let agent = match (&vlan, arrival) {
+                (None, Some(a)) => self.state.agents.read().unwrap().get(&a.ifindex).cloned(),
+                _ => None,
+            };
+            let txn = match peek_header(&buf[..len]) {
+                Some((xid, chaddr)) => self.state.transactions.span(xid, chaddr),
+                None => Span::none(),
+            };
+            let receive = info_span!(parent: &txn, "receive", src = %addr, len);
+            let msg = receive.in_scope(|| info_span!("decode").in_scope(|| decode(&buf[..len])));
+            let Ok(msg) = msg else {
+                warn!("failed decode msg");
+                metrics().inc(DECODE_FAILURES, &[("direction", "request")]);
+                let mut data = buf[..len].to_vec();
+                let iface = match (&vlan, &agent) {
+                    (Some(v), _) => Some(v.name.clone()),
+                    (None, Some(a)) => Some(a.name.clone()),
+                    (None, None) => self.state.interface_for_header(&data, addr),
+                };
+                let event = Event {
+                    interface: iface.as_deref(),
+                    rules: &passed,
+                    ..Event::from_header("request", UNDECODABLE, &data, addr)
+                };
+                if !passthrough(&self.state, &data, addr, "request", BOOTREQUEST) {
+                    report(&self.state, event.dropped("malformed"));
+                    continue;
+                }
+                if !self.state.is_forwarding(iface.as_deref()) {
+                    debug!("forwarding disabled on {:?}", iface);
+                    report(&self.state, event.dropped("disabled"));
+                    continue;
+                }
+                if data[3] >= MAX_HOPS {
+                    info!("drop undecodable request from {}: too many hops", addr);
+                    report(&self.state, event.dropped("max_hops"));
+                    continue;
+                }
+                // no rules apply to bytes we could not read; only the relay agent fields
+                data[3] += 1;
+                if let Some(vlan) = &vlan {
+                    vlan.relay_bootp(&mut data);
+                } else if let Some(agent) = &agent {
+                    fill_giaddr(&mut data, agent.address);
+                }
+                let event = event.dropped("channel");
+                let opaque = Opaque {
+                    data,
+                    client: addr,
+                    iface: iface.clone(),
+                    vlan,
+                    span: txn,
+                };
+                // counted before the send, so the sender never takes it below zero
+                metrics().add(CHANNEL_DEPTH, &[], 1.0);
+                if tx.send(Forward::Opaque(opaque)).await.is_err() {
+                    metrics().add(CHANNEL_DEPTH, &[], -1.0);
                     warn!("failed sending");
+                    report(&self.state, event);
                 }
-            } else {
-                warn!("failed decode msg")
+                continue;
+            };
+            info!("DHCP Message received!");
+            debug!("msg: {:?}", msg);
+            let mut msg = DHCPMessage::from(msg);
+            metrics().touch();
+            metrics().inc(
+                PACKETS_RECEIVED,
+                &[("direction", "request"), ("type", &msg.msg_type_name())],
+            );
+            let iface = match (&vlan, &agent) {
+                (Some(v), _) => Some(v.name.clone()),
+                (None, Some(a)) => Some(a.name.clone()),
+                (None, None) => self.state.interface_for(&msg, addr),
+            };
+            if let Some(capture) = &self.state.capture {
+                capture.write(
+                    &msg,
+                    &Record {
+                        direction: Direction::Inbound,
+                        form: "received",
+                        iface: iface.as_deref(),
+                        src: addr,
+                        dst: server_local,
+                        data: &buf[..len],
+                    },
+                );
+            }
+            let event = Event {
+                interface: iface.as_deref(),
+                ..Event::new("request", &msg, addr)
+            };
+            if !self.state.is_forwarding(iface.as_deref()) {
+                debug!("forwarding disabled on {:?}", iface);
+                report(&self.state, event.dropped("disabled"));
+                continue;
             }
+            let rules = self.state.rules();
+            let transform = info_span!(parent: &receive, "transform");
+            let class = match transform.in_scope(|| rules.acl.check(&msg)) {
+                Verdict::Allow(class) => class.cloned(),
+                Verdict::Deny(reason) => {
+                    info!("drop msg from {}: {}", format_mac(msg.chaddr()), reason);
+                    let applied = [format!("acl:{}", reason)];
+                    let event = Event {
+                        rules: &applied,
+                        ..event
+                    };
+                    report(&self.state, event.dropped("acl"));
+                    continue;
+                }
+            };
+            let boot = transform.in_scope(|| rules.pxe.select(&msg));
+            // made from the DISCOVER as received, before it is changed for the server
+            let offer = match (proxy_id, &boot) {
+                (Some(server_id), Some(boot)) if msg.msg_type() == Some(MessageType::Discover) => {
+                    Some(proxy::answer(&msg, boot, server_id, MessageType::Offer))
+                }
+                _ => None,
+            };
+            let mut bootp = None;
+            let mut applied = Vec::new();
+            if msg.is_bootp() {
+                let mut data = buf[..len].to_vec();
+                if data[3] >= MAX_HOPS {
+                    info!("drop BOOTP request from {}: too many hops", addr);
+                    report(&self.state, event.dropped("max_hops"));
+                    continue;
+                }
+                data[3] += 1;
+                bootp = Some(data);
+                applied.push(String::from("bootp"));
+            } else if let Some(c) = &class {
+                if transform.in_scope(|| c.apply(msg.raw_mut())) {
+                    applied.push(format!("options:{}", c.name));
+                }
+            }
+            if let Some(vlan) = &vlan {
+                match &mut bootp {
+                    Some(data) => vlan.relay_bootp(data),
+                    None => transform.in_scope(|| vlan.relay(msg.raw_mut())),
+                }
+                applied.push(format!("vlan:{}", vlan.name));
+            } else if let Some(agent) = &agent {
+                // the server picks the address from the attached interface's subnet
+                let filled = match &mut bootp {
+                    Some(data) => fill_giaddr(data, agent.address),
+                    None if msg.raw().giaddr().is_unspecified() => {
+                        msg.raw_mut().set_giaddr(agent.address);
+                        true
+                    }
+                    None => false,
+                };
+                if filled {
+                    applied.push(format!("giaddr:{}", agent.address));
+                }
+            } else if let (Some(data), Some(arrival)) = (&mut bootp, arrival) {
+                // the server answers a BOOTP request through the relay agent in giaddr
+                // ref: https://www.rfc-editor.org/rfc/rfc1542#section-4.1.1
+                if !arrival.local.is_unspecified() && fill_giaddr(data, arrival.local) {
+                    applied.push(format!("giaddr:{}", arrival.local));
+                }
+            }
+            // last, so the server sees giaddr in its own range as well
+            if let (Some(nat), None) = (&rules.nat, &bootp) {
+                transform.in_scope(|| nat.to_internal(msg.raw_mut()));
+                applied.push(String::from("nat"));
+            }
+            let event = event.dropped("channel");
+            let relayed = Relayed {
+                msg,
+                client: addr,
+                iface: iface.clone(),
+                class,
+                bootp,
+                boot,
+                offer,
+                vlan,
+                applied,
+                span: txn,
+            };
+            metrics().add(CHANNEL_DEPTH, &[], 1.0);
+            if tx.send(Forward::Message(Box::new(relayed))).await.is_err() {
+                metrics().add(CHANNEL_DEPTH, &[], -1.0);
+                warn!("failed sending");
+                report(&self.state, event);
+            }
+        }
+    }
+
+    // receives replies from the upstream server on the sender socket and hands them back
+    // to the client that sent the request with the same xid
+    async fn relay_replies(
+        reply_sock: Arc<UdpSocket>,
+        client_sock: Arc<UdpSocket>,
+        pending: Arc<Mutex<HashMap<u32, Pending>>>,
+        state: Arc<State>,
+    ) -> io::Result<()> {
+        let client_local = reply_sock.local_addr()?;
+        let server_local = client_sock.local_addr()?;
+        let mut buf = vec![0; recv_buffer_size().await];
+        loop {
+            let (len, addr, _) = match recv_datagram(&reply_sock, &mut buf, "reply").await {
+                Ok(Some(received)) => received,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("could not receive a reply: {}", e);
+                    continue;
+                }
+            };
+            // our own broadcasts to VLAN clients loop back to the client port
+            let looped = addr.port() == SERVER_PORT
+                && state
+                    .vlans
+                    .read()
+                    .unwrap()
+                    .values()
+                    .any(|v| addr.ip() == v.address);
+            if looped {
+                continue;
+            }
+            let received = &buf[..len];
+            relay_reply(
+                received,
+                addr,
+                client_local,
+                server_local,
+                &client_sock,
+                &pending,
+                &state,
+            )
+            .await;
         }
     }
 }
+
+// one reply from the upstream server. `local` is the address it was sent to, `server_local`
+// the one `client_sock` sends from.
+async fn relay_reply(
+    received: &[u8],
+    addr: SocketAddr,
+    local: SocketAddr,
+    server_local: SocketAddr,
+    client_sock: &UdpSocket,
+    pending: &Mutex<HashMap<u32, Pending>>,
+    state: &State,
+) {
+    let len = received.len();
+    let txn = peek_header(received)
+        .and_then(|(xid, chaddr)| state.transactions.get(xid, chaddr))
+        .unwrap_or_else(Span::none);
+    let reply = info_span!(parent: &txn, "upstream_reply", src = %addr, len);
+    // a vend field that is not RFC 1048 options is the client's business
+    if is_plain_bootp(received) && check_bootp(received, BOOTREPLY).is_ok() {
+        metrics().touch();
+        metrics().inc(PACKETS_RECEIVED, &[("direction", "reply"), ("type", BOOTP)]);
+        let event = Event::from_header("reply", BOOTP, received, addr);
+        relay_reply_bytes(received, client_sock, pending, state, event)
+            .instrument(reply)
+            .await;
+        return;
+    }
+    let decoded = reply.in_scope(|| decode(received));
+    let mut msg = match decoded {
+        Ok(msg) => DHCPMessage::from(msg),
+        Err(_) => {
+            warn!("failed decode reply from {}", addr);
+            metrics().inc(DECODE_FAILURES, &[("direction", "reply")]);
+            let passed = [String::from(UNDECODABLE)];
+            let event = Event {
+                rules: &passed,
+                ..Event::from_header("reply", UNDECODABLE, received, addr)
+            };
+            if !passthrough(state, received, addr, "reply", BOOTREPLY) {
+                report(state, event.dropped("malformed"));
+                return;
+            }
+            relay_reply_bytes(received, client_sock, pending, state, event).await;
+            return;
+        }
+    };
+    metrics().touch();
+    metrics().inc(
+        PACKETS_RECEIVED,
+        &[("direction", "reply"), ("type", &msg.msg_type_name())],
+    );
+    let p = pending.lock().unwrap().remove(&msg.xid());
+    let Some(p) = p else {
+        info!(
+            "reply from {} does not match any request (xid: {:#x})",
+            addr,
+            msg.xid()
+        );
+        report(
+            state,
+            Event::new("reply", &msg, addr).dropped("unknown_xid"),
+        );
+        return;
+    };
+    let mut applied = Vec::new();
+    let mut rewritten = false;
+    // BOOTP replies go out as received (see `Relayed::bootp`)
+    if let Some(nat) = &state.rules().nat {
+        if !msg.is_bootp() {
+            info_span!(parent: &txn, "transform").in_scope(|| nat.to_external(msg.raw_mut()));
+            applied.push(String::from("nat"));
+            rewritten = true;
+        }
+    }
+    if matches!(msg.msg_type(), Some(MessageType::Offer | MessageType::Ack)) {
+        let _transform = info_span!(parent: &txn, "transform").entered();
+        if let Some(boot) = &p.boot {
+            boot.apply(msg.raw_mut());
+            applied.push(format!("pxe:{}", boot.profile));
+            rewritten = true;
+        }
+        if state
+            .dns
+            .as_ref()
+            .is_some_and(|d| d.resolver().apply(msg.raw_mut()))
+        {
+            applied.push(String::from("dns"));
+            rewritten = true;
+        }
+        let route_options = state.rules().route_options.clone();
+        if route_options.enabled {
+            let route_info = p
+                .iface
+                .as_ref()
+                .and_then(|i| state.route_info.read().unwrap().get(i).cloned());
+            if route_info.is_some_and(|r| r.apply(msg.raw_mut(), route_options.overwrite)) {
+                applied.push(String::from("route-options"));
+                rewritten = true;
+            }
+        }
+    }
+    if p.vlan.is_some() && !msg.is_bootp() && vlan::strip(msg.raw_mut()) {
+        applied.push(String::from("option-82"));
+        rewritten = true;
+    }
+    let dst = client_addr(&p, msg.raw().ciaddr());
+    let data = if rewritten || len > p.max_size {
+        match msg.encode_within(p.max_size) {
+            Ok(data) => {
+                if len > p.max_size {
+                    applied.push(format!("max-size:{}", p.max_size));
+                }
+                data
+            }
+            Err(e) => {
+                warn!("reply for {} does not fit: {}", dst, e);
+                let event = Event {
+                    interface: p.iface.as_deref(),
+                    dst: Some(dst),
+                    ..Event::new("reply", &msg, addr)
+                };
+                report(state, event.dropped("too_large"));
+                return;
+            }
+        }
+    } else {
+        received.to_vec()
+    };
+    if let Some(capture) = &state.capture {
+        capture.write(
+            &msg,
+            &Record {
+                direction: Direction::Inbound,
+                form: "received",
+                iface: p.iface.as_deref(),
+                src: addr,
+                dst: local,
+                data: received,
+            },
+        );
+        capture.write(
+            &msg,
+            &Record {
+                direction: Direction::Outbound,
+                form: "transformed",
+                iface: p.iface.as_deref(),
+                src: server_local,
+                dst,
+                data: &data,
+            },
+        );
+    }
+    metrics().observe(
+        REPLY_LATENCY,
+        &[("upstream", &p.upstream.to_string())],
+        p.sent.elapsed(),
+    );
+    let event = Event {
+        interface: p.iface.as_deref(),
+        dst: Some(dst),
+        rules: &applied,
+        ..Event::new("reply", &msg, addr)
+    };
+    let deliver = info_span!(parent: &txn, "deliver", client = %dst);
+    // a BOOTP exchange is over with its single reply
+    if msg.is_bootp() || matches!(msg.msg_type(), Some(MessageType::Ack | MessageType::Nak)) {
+        state.transactions.finish(msg.xid(), msg.chaddr());
+    }
+    if let Err(e) = send_reply(client_sock, &data, dst, p.vlan.as_ref())
+        .instrument(deliver)
+        .await
+    {
+        warn!("could not send reply to {}: {}", dst, e);
+        report(state, event.dropped("send_error"));
+    } else {
+        report(state, event);
+    }
+}
+
+// waits for the reply to `xid`. entered before the request is sent, as a fast server can
+// answer before `send_to` returns.
+fn expect_reply(pending: &Mutex<HashMap<u32, Pending>>, xid: u32, p: Pending) {
+    let mut pending = pending.lock().unwrap();
+    pending.retain(|_, p| p.sent.elapsed() < PENDING_TIMEOUT);
+    pending.insert(xid, p);
+}
+
+// sends an undecodable request upstream the way the sender task sends the others
+async fn forward_opaque(
+    sock: &UdpSocket,
+    opaque: Opaque,
+    server_host: SocketAddr,
+    runtime_ip: &str,
+    state: &State,
+    pending: &Mutex<HashMap<u32, Pending>>,
+) {
+    let Opaque {
+        data,
+        client,
+        iface,
+        vlan,
+        span,
+    } = opaque;
+    let passed = [String::from(UNDECODABLE)];
+    let event = Event {
+        interface: iface.as_deref(),
+        rules: &passed,
+        ..Event::from_header("request", UNDECODABLE, &data, client)
+    };
+    // clients on a VLAN reach us directly, not through the runtime
+    if vlan.is_none() && client.ip().to_string() != runtime_ip {
+        info!("addr is not from runtime?");
+        report(state, event.dropped("not_runtime"));
+        return;
+    }
+    let upstream = vlan
+        .as_ref()
+        .and_then(|v| v.upstream)
+        .unwrap_or(server_host);
+    let event = Event {
+        dst: Some(upstream),
+        ..event
+    };
+    let xid = peek_header(&data).map(|(xid, _)| xid);
+    if let Some(xid) = xid {
+        let p = Pending {
+            client,
+            upstream,
+            iface: iface.clone(),
+            sent: Instant::now(),
+            max_size: MAX_DATAGRAM,
+            boot: None,
+            vlan,
+        };
+        expect_reply(pending, xid, p);
+    }
+    let forward = info_span!(parent: &span, "forward", upstream = %upstream);
+    if let Err(e) = sock.send_to(&data, upstream).instrument(forward).await {
+        if let Some(xid) = xid {
+            pending.lock().unwrap().remove(&xid);
+        }
+        warn!("could not send to {}: {}", upstream, e);
+        metrics().inc(UPSTREAM_SEND_ERRORS, &[("upstream", &upstream.to_string())]);
+        report(state, event.dropped("send_error"));
+        return;
+    }
+    report(state, event);
+}
+
+// a reply that is not decoded, to the client of the request with its xid, as received
+async fn relay_reply_bytes(
+    received: &[u8],
+    client_sock: &UdpSocket,
+    pending: &Mutex<HashMap<u32, Pending>>,
+    state: &State,
+    event: Event<'_>,
+) {
+    let p = peek_header(received).and_then(|(xid, _)| pending.lock().unwrap().remove(&xid));
+    let Some(p) = p else {
+        info!(
+            "{} reply from {} does not match any request",
+            event.message_type, event.src
+        );
+        report(state, event.dropped("unknown_xid"));
+        return;
+    };
+    let dst = client_addr(&p, Ipv4Addr::UNSPECIFIED);
+    let event = Event {
+        interface: p.iface.as_deref(),
+        dst: Some(dst),
+        ..event
+    };
+    metrics().observe(
+        REPLY_LATENCY,
+        &[("upstream", &p.upstream.to_string())],
+        p.sent.elapsed(),
+    );
+    if let Some((xid, chaddr)) = peek_header(received) {
+        state.transactions.finish(xid, chaddr);
+    }
+    match send_reply(client_sock, received, dst, p.vlan.as_ref()).await {
+        Ok(_) => report(state, event),
+        Err(e) => {
+            warn!("could not send reply to {}: {}", dst, e);
+            report(state, event.dropped("send_error"));
+        }
+    }
+}
+
+// where a reply goes: back to where the request came from, or for a client on a VLAN
+// (which has no address yet unless ciaddr is set) to the VLAN's broadcast address
+// ref: https://www.rfc-editor.org/rfc/rfc2131#section-4.1
+fn client_addr(p: &Pending, ciaddr: Ipv4Addr) -> SocketAddr {
+    match &p.vlan {
+        Some(_) if !ciaddr.is_unspecified() => SocketAddr::from((ciaddr, CLIENT_PORT)),
+        Some(_) => SocketAddr::from((Ipv4Addr::BROADCAST, CLIENT_PORT)),
+        None => p.client,
+    }
+}
+
+// send_to, but out of the VLAN's sub-interface and from its address when there is one,
+// as a broadcast would otherwise leave through the default route
+async fn send_reply(
+    sock: &UdpSocket,
+    data: &[u8],
+    dst: SocketAddr,
+    vlan: Option<&Vlan>,
+) -> io::Result<usize> {
+    let (Some(vlan), SocketAddr::V4(dst)) = (vlan, dst) else {
+        return sock.send_to(data, dst).await;
+    };
+    let info = libc::in_pktinfo {
+        ipi_ifindex: vlan.index as i32,
+        ipi_spec_dst: libc::in_addr {
+            s_addr: u32::from(vlan.address).to_be(),
+        },
+        ipi_addr: libc::in_addr { s_addr: 0 },
+    };
+    sock.async_io(Interest::WRITABLE, || {
+        let iov = [IoSlice::new(data)];
+        let cmsgs = [ControlMessage::Ipv4PacketInfo(&info)];
+        let addr = SockaddrIn::from(dst);
+        Ok(sendmsg(
+            sock.as_raw_fd(),
+            &iov,
+            &cmsgs,
+            MsgFlags::MSG_DONTWAIT,
+            Some(&addr),
+        )?)
+    })
+    .await
+}
+
+// requests from clients and replies to giaddr come in here; the interface a request came
+// in on tells its VLAN, and replies to VLAN clients are broadcast
+async fn bind_server_port() -> io::Result<UdpSocket> {
+    let sock = UdpSocket::bind(format!("0.0.0.0:{}", SERVER_PORT)).await?;
+    setsockopt(&sock, sockopt::Ipv4PacketInfo, &true)?;
+    sock.set_broadcast(true)?;
+    Ok(sock)
+}
+
+// decides on a message that failed to decode, dumping a sample of it now and then.
+// true when it should be forwarded as-is.
+fn passthrough(state: &State, data: &[u8], src: SocketAddr, direction: &str, op: u8) -> bool {
+    let rules = state.rules();
+    if state.should_dump(rules.malformed.dump_interval) {
+        info!(
+            "undecodable {} from {} ({} bytes):\n{}",
+            direction,
+            src,
+            data.len(),
+            hex_dump(data)
+        );
+    }
+    if rules.malformed.policy == MalformedPolicy::Strict {
+        return false;
+    }
+    match check_bootp(data, op) {
+        Ok(()) => true,
+        Err(reason) => {
+            info!("not passing through {} from {}: {}", direction, src, reason);
+            false
+        }
+    }
+}
+
+// receive buffers fit a datagram at the largest MTU of the host's links, so one that came
+// in fragments shows up as truncated
+pub(crate) async fn recv_buffer_size() -> usize {
+    match largest_mtu().await {
+        Ok(mtu) => buffer_size(mtu),
+        Err(e) => {
+            warn!("could not read link MTUs: {}", e);
+            MAX_DATAGRAM
+        }
+    }
+}
+
+// where a datagram came in, on sockets with IP_PKTINFO set
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Arrival {
+    pub ifindex: u32,
+    // the address of the interface for a broadcast, otherwise the one it was sent to
+    pub local: Ipv4Addr,
+}
+
+// recv_from that notices datagrams larger than `buf` (MSG_TRUNC) and drops them,
+// instead of handing a cut-off message to the decoder
+pub(crate) async fn recv_datagram(
+    sock: &UdpSocket,
+    buf: &mut [u8],
+    direction: &str,
+) -> io::Result<Option<(usize, SocketAddr, Option<Arrival>)>> {
+    let (len, addr, arrival, truncated) = sock
+        .async_io(Interest::READABLE, || {
+            let mut iov = [IoSliceMut::new(buf)];
+            let mut cmsg = cmsg_space!(libc::in_pktinfo);
+            let msg = recvmsg::<SockaddrIn>(
+                sock.as_raw_fd(),
+                &mut iov,
+                Some(&mut cmsg),
+                MsgFlags::MSG_DONTWAIT,
+            )?;
+            let addr = msg
+                .address
+                .map(|a| SocketAddr::V4(SocketAddrV4::from(a)))
+                .ok_or_else(|| io::Error::other("datagram without source address"))?;
+            let arrival = msg.cmsgs().find_map(|c| match c {
+                ControlMessageOwned::Ipv4PacketInfo(info) => Some(Arrival {
+                    ifindex: info.ipi_ifindex as u32,
+                    local: Ipv4Addr::from(u32::from_be(info.ipi_spec_dst.s_addr)),
+                }),
+                _ => None,
+            });
+            let truncated = msg.flags.contains(MsgFlags::MSG_TRUNC);
+            Ok((msg.bytes, addr, arrival, truncated))
+        })
+        .await?;
+    if truncated {
+        warn!(
+            "dropped datagram from {}: larger than {} bytes",
+            addr,
+            buf.len()
+        );
+        metrics().inc(
+            PACKETS_DROPPED,
+            &[
+                ("direction", direction),
+                ("type", "none"),
+                ("reason", "truncated"),
+            ],
+        );
+        return Ok(None);
+    }
+    Ok(Some((len, addr, arrival)))
+}
+
+// records the final action taken on a message in metrics and the event log
+pub(crate) fn report(state: &State, event: Event) {
+    let labels = [
+        ("direction", event.direction),
+        ("type", event.message_type.as_str()),
+    ];
+    // undecodable datagrams passed through are counted on their own
+    match event.reason {
+        Some(reason) => metrics().inc(PACKETS_DROPPED, &[labels[0], labels[1], ("reason", reason)]),
+        None if event.message_type == UNDECODABLE => {
+            metrics().inc(PASSTHROUGH_FORWARDED, &labels[..1])
+        }
+        None => metrics().inc(PACKETS_FORWARDED, &labels),
+    }
+    if let Some(event_log) = &state.event_log {
+        event_log.write(&event);
+    }
+}
@@ -0,0 +1,395 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    ops::Range,
+    path::PathBuf,
+    sync::{Arc, Mutex, PoisonError, RwLock},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use crate::{
+    acl::Acl,
+    capture::Capture,
+    config::{Config, MalformedConfig, RouteOptionsConfig},
+    dns::Dns,
+    eventlog::EventLog,
+    nat::Nat,
+    packet::{DHCPMessage, CIADDR, GIADDR},
+    process::ProcessExecutor,
+    pxe::Pxe,
+    route::RouteInfo,
+    telemetry::Transactions,
+    vlan::Vlan,
+    watch::{self, Agent, Attached},
+};
+
+// reloads closer together than this are refused
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+// objects created by `setup_ns`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resource {
+    Netns(String),
+    Link {
+        name: String,
+        netns: Option<String>,
+        address: Ipv4Addr,
+        prefix: u8,
+    },
+    Route {
+        destination: Ipv4Addr,
+        prefix: u8,
+        gateway: Ipv4Addr,
+        table: Option<u32>,
+        netns: Option<String>,
+    },
+    Rule {
+        from: Ipv4Addr,
+        table: u32,
+        netns: String,
+    },
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Resource::Netns(name) => write!(f, "netns {}", name),
+            Resource::Link {
+                name,
+                netns,
+                address,
+                prefix,
+            } => write!(
+                f,
+                "link {} netns={} address={}/{}",
+                name,
+                netns.as_deref().unwrap_or("-"),
+                address,
+                prefix
+            ),
+            Resource::Route {
+                destination,
+                prefix,
+                gateway,
+                table,
+                netns,
+            } => {
+                write!(f, "route {}/{} via {}", destination, prefix, gateway)?;
+                if let Some(table) = table {
+                    write!(f, " table={}", table)?;
+                }
+                write!(f, " netns={}", netns.as_deref().unwrap_or("-"))
+            }
+            Resource::Rule { from, table, netns } => {
+                write!(f, "rule from {} table={} netns={}", from, table, netns)
+            }
+        }
+    }
+}
+
+// everything the relay consults per packet; replaced as a whole on reload so a
+// packet never sees half of an old and half of a new configuration
+#[derive(Debug, Default)]
+pub struct Rules {
+    pub acl: Acl,
+    pub malformed: MalformedConfig,
+    pub pxe: Pxe,
+    pub route_options: RouteOptionsConfig,
+    pub nat: Option<Nat>,
+}
+
+impl Rules {
+    pub fn new(config: &Config) -> io::Result<Self> {
+        Ok(Self {
+            acl: Acl::new(&config.acl)?,
+            malformed: config.malformed.clone(),
+            pxe: Pxe::new(&config.pxe),
+            route_options: config.route_options.clone(),
+            nat: Nat::new(&config.nat)?,
+        })
+    }
+}
+
+impl fmt::Display for Rules {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.acl, self.pxe)?;
+        if let Some(nat) = &self.nat {
+            write!(f, "{}", nat)?;
+        }
+        writeln!(
+            f,
+            "malformed policy={} dump-interval={}s",
+            format!("{:?}", self.malformed.policy).to_lowercase(),
+            self.malformed.dump_interval
+        )?;
+        if self.route_options.enabled {
+            writeln!(
+                f,
+                "route-options overwrite={}",
+                self.route_options.overwrite
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// state shared between the relay and the control socket
+#[derive(Debug, Default)]
+pub struct State {
+    pub config_path: Option<PathBuf>,
+    pub config: RwLock<Config>,
+    pub rules: RwLock<Arc<Rules>>,
+    pub route_info: RwLock<HashMap<String, RouteInfo>>,
+    pub resources: Mutex<Vec<Resource>>,
+    // host interfaces with a veth pair into the namespace
+    pub attached: Mutex<HashMap<String, Attached>>,
+    // configured VLANs by the ifindex of their sub-interface
+    pub vlans: RwLock<HashMap<u32, Vlan>>,
+    // attached interfaces with an address in the server's subnet, by ifindex
+    pub agents: RwLock<HashMap<u32, Agent>>,
+    pub(crate) syncing: Mutex<()>,
+    pub child: Mutex<Option<ProcessExecutor>>,
+    pub disabled: RwLock<HashSet<String>>,
+    pub capture: Option<Capture>,
+    pub event_log: Option<EventLog>,
+    pub dns: Option<Dns>,
+    pub transactions: Transactions,
+    // the DHCP server's address, for setting up links on reload
+    pub server: Option<Ipv4Addr>,
+    last_dump: Mutex<Option<Instant>>,
+    last_reload: Mutex<Option<Instant>>,
+}
+
+impl State {
+    pub fn new(config_path: Option<PathBuf>, config: Config, server: Ipv4Addr) -> io::Result<Self> {
+        let rules = Rules::new(&config)?;
+        let capture = match &config.capture.path {
+            Some(path) => Some(Capture::new(path, &config.capture)?),
+            None => None,
+        };
+        let event_log = match &config.event_log.path {
+            Some(path) => Some(EventLog::new(path)?),
+            None => None,
+        };
+        let dns = match &config.dns.resolv_conf {
+            Some(path) => Some(Dns::new(path)?),
+            None => None,
+        };
+        Ok(Self {
+            config_path,
+            capture,
+            event_log,
+            dns,
+            config: RwLock::new(config),
+            rules: RwLock::new(Arc::new(rules)),
+            server: Some(server),
+            ..Default::default()
+        })
+    }
+
+    pub fn rules(&self) -> Arc<Rules> {
+        Arc::clone(&self.rules.read().unwrap())
+    }
+
+    pub fn set_child(&self, executor: ProcessExecutor) {
+        *self.child.lock().unwrap() = Some(executor);
+    }
+
+    // re-reads the config file and swaps the rules in one step. nothing is applied if the
+    // new config does not load, or if syncing a changed `network` fails. pending
+    // transactions live in the relay and are kept. sections bound at startup (sockets, the
+    // namespace, the route source) keep their running values; the links and VLANs are
+    // synced with a changed `network`. blocks on netlink, so call it outside the runtime.
+    pub fn reload(&self) -> io::Result<Vec<String>> {
+        let Some(path) = &self.config_path else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "started without --config",
+            ));
+        };
+        // a reload may set up links; a burst of SIGHUPs or `reload`s runs it once. held
+        // throughout, so a concurrent one waits and is then refused
+        let mut last = self
+            .last_reload
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if last.is_some_and(|t| t.elapsed() < RELOAD_INTERVAL) {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!(
+                    "last reload less than {}s ago; try again later",
+                    RELOAD_INTERVAL.as_secs()
+                ),
+            ));
+        }
+        let mut new = Config::load(path)?;
+        let rules = Rules::new(&new)?;
+
+        let current = self.config.read().unwrap().clone();
+        let mut changes = Vec::new();
+        if current.acl != new.acl {
+            changes.push(String::from("acl: applied"));
+        }
+        if current.malformed != new.malformed {
+            changes.push(String::from("malformed: applied"));
+        }
+        if current.pxe != new.pxe {
+            changes.push(String::from("pxe: applied"));
+        }
+        if current.route_options != new.route_options {
+            changes.push(String::from("route-options: applied"));
+        }
+        if current.nat != new.nat {
+            changes.push(String::from("nat: applied"));
+        }
+
+        let (network, bound) = (&current.network, &new.network);
+        let network_bound = network.netns != bound.netns
+            || network.create_netns != bound.create_netns
+            || network.link != bound.link
+            || network.peer != bound.peer
+            || network.route_file != bound.route_file
+            || network.route_source != bound.route_source
+            || network.watch != bound.watch;
+        let network_live = network.routes != bound.routes
+            || network.backend != bound.backend
+            || network.backends != bound.backends
+            || network.link_properties != bound.link_properties
+            || network.vlans != bound.vlans;
+        let restart_only = [
+            ("metrics", current.metrics != new.metrics),
+            ("ctl", current.ctl != new.ctl),
+            ("network", network_bound),
+            ("capture", current.capture != new.capture),
+            ("event-log", current.event_log != new.event_log),
+            ("tracing", current.tracing != new.tracing),
+            ("proxy-dhcp", current.proxy_dhcp != new.proxy_dhcp),
+            ("dns", current.dns != new.dns),
+        ];
+        let restart: Vec<&str> = restart_only
+            .into_iter()
+            .filter_map(|(section, changed)| changed.then_some(section))
+            .collect();
+        new.metrics = current.metrics;
+        new.ctl = current.ctl;
+        new.network.netns = current.network.netns;
+        new.network.create_netns = current.network.create_netns;
+        new.network.link = current.network.link;
+        new.network.peer = current.network.peer;
+        new.network.route_file = current.network.route_file;
+        new.network.route_source = current.network.route_source;
+        new.network.watch = current.network.watch;
+        new.capture = current.capture;
+        new.event_log = current.event_log;
+        new.tracing = current.tracing;
+        new.proxy_dhcp = current.proxy_dhcp;
+        new.dns = current.dns;
+
+        // the links go first; the config and rules are only swapped in once they are set up
+        if let (true, Some(ip)) = (network_live, self.server) {
+            changes.push(String::from("network: applied"));
+            changes.extend(watch::sync_network(self, &new.network, ip)?);
+        }
+        for section in restart {
+            warn!("reload: `{}` changed; takes effect after restart", section);
+            changes.push(format!("{}: changed; takes effect after restart", section));
+        }
+        {
+            let mut config = self.config.write().unwrap();
+            let mut current = self.rules.write().unwrap();
+            *config = new;
+            *current = Arc::new(rules);
+        }
+        *last = Some(Instant::now());
+
+        info!("reloaded {}", path.display());
+        Ok(changes)
+    }
+
+    // the interface whose subnet contains giaddr, ciaddr or the source address (in this order).
+    // falls back to the only interface when there is just one.
+    pub fn interface_for(&self, msg: &DHCPMessage, src: SocketAddr) -> Option<String> {
+        let raw = msg.raw();
+        self.interface_by(vec![raw.giaddr(), raw.ciaddr()], src)
+    }
+
+    // `interface_for` a message that did not decode, from its fixed header
+    pub fn interface_for_header(&self, data: &[u8], src: SocketAddr) -> Option<String> {
+        let addr = |r: Range<usize>| {
+            let octets = <[u8; 4]>::try_from(data.get(r)?).ok()?;
+            Some(Ipv4Addr::from(octets))
+        };
+        let candidates = [addr(GIADDR), addr(CIADDR)].into_iter().flatten();
+        self.interface_by(candidates.collect(), src)
+    }
+
+    fn interface_by(&self, mut candidates: Vec<Ipv4Addr>, src: SocketAddr) -> Option<String> {
+        let route_info = self.route_info.read().unwrap();
+        if let IpAddr::V4(v) = src.ip() {
+            candidates.push(v);
+        }
+        for ip in candidates.into_iter().filter(|ip| !ip.is_unspecified()) {
+            if let Some((k, _)) = route_info.iter().find(|(_, v)| v.contains(ip)) {
+                return Some(k.clone());
+            }
+        }
+        if route_info.len() == 1 {
+            return route_info.keys().next().cloned();
+        }
+        None
+    }
+
+    // true at most once every `interval` seconds, never when it is 0
+    pub fn should_dump(&self, interval: u64) -> bool {
+        if interval == 0 {
+            return false;
+        }
+        let mut last = self.last_dump.lock().unwrap();
+        if last.is_some_and(|t| t.elapsed() < Duration::from_secs(interval)) {
+            return false;
+        }
+        *last = Some(Instant::now());
+        true
+    }
+
+    pub fn is_forwarding(&self, iface: Option<&str>) -> bool {
+        match iface {
+            Some(iface) => !self.disabled.read().unwrap().contains(iface),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::config::AclConfig;
+
+    #[test]
+    fn rejected_reload_is_not_counted() {
+        let path = std::env::temp_dir().join(format!("middle-sock-state-{}", std::process::id()));
+        let state = State {
+            config_path: Some(path.clone()),
+            ..Default::default()
+        };
+        fs::write(&path, "[acl]\ndeny = [\"nonsense\"]\n").unwrap();
+        let rejected = state.reload();
+        let kept = state.config.read().unwrap().acl.clone();
+        fs::write(&path, "[acl]\ndeny = [\"mac:02:00:00:00:00:01\"]\n").unwrap();
+        let retried = state.reload();
+        let refused = state.reload();
+        fs::remove_file(&path).unwrap();
+
+        assert!(rejected.is_err());
+        assert_eq!(kept, AclConfig::default());
+        assert_eq!(retried.unwrap(), vec![String::from("acl: applied")]);
+        assert_eq!(state.config.read().unwrap().acl.deny.len(), 1);
+        assert!(state.rules().acl.to_string().contains("02:00:00:00:00:01"));
+        assert_eq!(refused.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+}
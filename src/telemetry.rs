@@ -0,0 +1,91 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::info;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use tracing::{info_span, Span};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+use crate::{config::TracingConfig, packet::format_mac};
+
+// a transaction without messages for this long is closed
+const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(60);
+
+// installs the OTLP exporter; spans are discarded when no endpoint is configured.
+// the batch exporter is spawned on the current tokio runtime.
+pub fn init(config: &TracingConfig) -> io::Result<()> {
+    let Some(endpoint) = &config.otlp_endpoint else {
+        return Ok(());
+    };
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                config.service_name.clone(),
+            )])),
+        )
+        .install_batch(runtime::Tokio)
+        .map_err(io::Error::other)?;
+    tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .try_init()
+        .map_err(io::Error::other)?;
+    info!("exporting traces to {}", endpoint);
+    Ok(())
+}
+
+// exports the spans still queued; call from outside the runtime the exporter runs on
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+// one root span per DHCP transaction (xid and chaddr, as clients may pick the same xid);
+// every stage of every message in the exchange (DISCOVER/OFFER/REQUEST/ACK) is a child of it
+#[derive(Debug, Default)]
+pub struct Transactions(Mutex<HashMap<TransactionKey, (Span, Instant)>>);
+
+// xid and chaddr
+type TransactionKey = (u32, Vec<u8>);
+
+impl Transactions {
+    pub fn span(&self, xid: u32, chaddr: &[u8]) -> Span {
+        let mut map = self.0.lock().unwrap();
+        map.retain(|_, (_, last)| last.elapsed() < TRANSACTION_TIMEOUT);
+        let (span, last) = map.entry((xid, chaddr.to_vec())).or_insert_with(|| {
+            let span = info_span!(
+                parent: None,
+                "dhcp.transaction",
+                xid = format!("{:#010x}", xid),
+                chaddr = format_mac(chaddr)
+            );
+            (span, Instant::now())
+        });
+        *last = Instant::now();
+        span.clone()
+    }
+
+    pub fn get(&self, xid: u32, chaddr: &[u8]) -> Option<Span> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(&(xid, chaddr.to_vec()))
+            .map(|(span, _)| span.clone())
+    }
+
+    // closes the root span once the last clone of it is dropped
+    pub fn finish(&self, xid: u32, chaddr: &[u8]) {
+        self.0.lock().unwrap().remove(&(xid, chaddr.to_vec()));
+    }
+}
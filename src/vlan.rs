@@ -0,0 +1,161 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+};
+
+use dhcproto::v4::{
+    relay::{RelayAgentInformation, RelayInfo},
+    DhcpOption, Message, OptionCode,
+};
+use ipnet::Ipv4Net;
+use log::{info, warn};
+use nix::net::if_::if_nametoindex;
+
+use crate::{
+    config::VlanConfig,
+    network::{link_addresses, link_exists},
+    packet::fill_giaddr,
+    plan::Step,
+    route::{RouteEntry, RouteInfo},
+    run_steps,
+    state::Resource,
+};
+
+// a configured VLAN whose sub-interface is up; `State::vlans` keys them by ifindex
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vlan {
+    pub name: String,
+    pub index: u32,
+    // giaddr of requests relayed from the VLAN
+    pub address: Ipv4Addr,
+    pub circuit_id: Vec<u8>,
+    pub upstream: Option<SocketAddr>,
+}
+
+impl Vlan {
+    // sets giaddr and adds option 82 to a request received on the VLAN. requests that
+    // already passed a relay agent (giaddr set) are left to the server as they are.
+    // ref: https://www.rfc-editor.org/rfc/rfc3046#section-2.1
+    pub fn relay(&self, msg: &mut Message) {
+        msg.set_hops(msg.hops().saturating_add(1));
+        if !msg.giaddr().is_unspecified() {
+            return;
+        }
+        msg.set_giaddr(self.address);
+        let opts = msg.opts_mut();
+        if opts.get(OptionCode::RelayAgentInformation).is_none() {
+            let mut info = RelayAgentInformation::default();
+            info.insert(RelayInfo::AgentCircuitId(self.circuit_id.clone()));
+            opts.insert(DhcpOption::RelayAgentInformation(info));
+        }
+    }
+
+    // BOOTP requests go out as received, so only giaddr is filled in
+    pub fn relay_bootp(&self, data: &mut [u8]) {
+        fill_giaddr(data, self.address);
+    }
+}
+
+// option 82 is between the relay agent and the server; clients never see it
+pub fn strip(msg: &mut Message) -> bool {
+    msg.opts_mut()
+        .remove(OptionCode::RelayAgentInformation)
+        .is_some()
+}
+
+// the steps that bring up a VLAN's sub-interface; an existing one is used as it is
+pub fn steps(vlan: &VlanConfig, exists: bool, addresses: &[Ipv4Addr]) -> Vec<Step> {
+    let name = vlan.name();
+    let mut steps = Vec::new();
+    if !exists {
+        steps.push(Step::CreateVlan {
+            link: name.clone(),
+            parent: vlan.parent.clone(),
+            id: vlan.id,
+        });
+    }
+    if !addresses.contains(&vlan.address.addr()) {
+        steps.push(Step::AddAddress {
+            link: name.clone(),
+            netns: None,
+            address: vlan.address.addr(),
+            prefix: vlan.address.prefix_len(),
+        });
+    }
+    steps.push(Step::LinkUp {
+        link: name,
+        netns: None,
+    });
+    steps
+}
+
+// creates the missing sub-interfaces. returns what was created, the VLANs by ifindex and
+// the ones that failed; a failed VLAN's new sub-interface is removed again.
+pub fn setup(vlans: &[VlanConfig]) -> (Vec<Resource>, HashMap<u32, Vlan>, Vec<String>) {
+    let mut resources = Vec::new();
+    let mut up = HashMap::new();
+    let mut failed = Vec::new();
+    for vlan in vlans {
+        let name = vlan.name();
+        match setup_one(vlan) {
+            Ok((created, index)) => {
+                resources.extend(created);
+                up.insert(
+                    index,
+                    Vlan {
+                        name,
+                        index,
+                        address: vlan.address.addr(),
+                        circuit_id: vlan.circuit_id().into_bytes(),
+                        upstream: vlan.upstream,
+                    },
+                );
+            }
+            Err(e) => {
+                warn!("could not set up {}: {}", name, e);
+                failed.push(format!("{}: setup failed: {}", name, e));
+            }
+        }
+    }
+    (resources, up, failed)
+}
+
+fn setup_one(vlan: &VlanConfig) -> io::Result<(Vec<Resource>, u32)> {
+    let name = vlan.name();
+    let steps = steps(vlan, link_exists(&name), &link_addresses(&name)?);
+    let created = run_steps(&steps)?;
+    for step in steps.iter().filter(|s| !matches!(s, Step::LinkUp { .. })) {
+        info!("{}", step);
+    }
+    Ok((created, if_nametoindex(name.as_str())?))
+}
+
+// the VLANs as interfaces of the route table. a sub-interface missing from the table (e.g.
+// a route file copied from elsewhere) gets its connected subnet, and its router as the
+// default route unless the table has one.
+pub fn merge(vlans: &[VlanConfig], route_info: &mut HashMap<String, RouteInfo>) {
+    for vlan in vlans {
+        let info = route_info.entry(vlan.name()).or_default();
+        let subnet = vlan.address.trunc();
+        if !info.subnets.iter().any(|e| e.destination == subnet) {
+            info.subnets.push(entry(subnet, None));
+        }
+        if let (None, Some(gateway)) = (info.default, vlan.gateway) {
+            info.default = Some(entry(Ipv4Net::default(), Some(gateway)));
+        }
+    }
+}
+
+fn entry(destination: Ipv4Net, gateway: Option<Ipv4Addr>) -> RouteEntry {
+    RouteEntry {
+        destination,
+        gateway,
+        metric: 0,
+        mtu: 0,
+        window: 0,
+        ref_cnt: 0,
+        use_field: 0,
+        irtt: 0,
+    }
+}
@@ -0,0 +1,353 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::Ipv4Addr,
+    path::Path,
+    sync::{Arc, PoisonError},
+    thread,
+    time::Duration,
+};
+
+use futures::StreamExt;
+use ipnet::Ipv4Net;
+use log::{debug, info, warn};
+use netlink_sys::{AsyncSocket, SocketAddr};
+use nix::{
+    errno::Errno,
+    net::if_::if_nametoindex,
+    sys::inotify::{AddWatchFlags, InitFlags, Inotify},
+};
+use rtnetlink::{
+    constants::{RTMGRP_IPV4_IFADDR, RTMGRP_IPV4_ROUTE, RTMGRP_LINK},
+    new_connection,
+};
+use tokio::sync::Notify;
+
+use crate::{
+    config::{LinkBackend, LinkProperties, NetworkConfig, NsRoutesConfig, RouteSource},
+    network::link_addresses,
+    new_route,
+    plan::Step,
+    route::RouteInfo,
+    setup_ns,
+    state::Resource,
+    state::State,
+    vlan,
+};
+
+// changes come in bursts (a new VLAN brings a link, an address and routes),
+// so the table is read once they settle
+const SETTLE: Duration = Duration::from_secs(1);
+// how often a replaced route file is looked for again
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+// a host interface with a veth pair into the namespace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attached {
+    // host side of the veth pair, or the macvlan/ipvlan child
+    pub link: String,
+    // where `link` is; deleting it removes the attachment
+    pub netns: Option<String>,
+    // subnet the addresses were picked from
+    pub subnet: Ipv4Net,
+    // what setup_ns created for the interface
+    pub resources: Vec<Resource>,
+    pub setup: LinkSetup,
+}
+
+// an attached interface as a relay agent; `State::agents` keys them by ifindex
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Agent {
+    pub name: String,
+    // giaddr of requests relayed from the interface
+    pub address: Ipv4Addr,
+}
+
+// the settings of `network` a link was set up with; when they change (on reload), the
+// link is removed and set up again
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkSetup {
+    pub backend: LinkBackend,
+    pub routes: NsRoutesConfig,
+    pub properties: LinkProperties,
+}
+
+impl LinkSetup {
+    pub fn new(network: &NetworkConfig, iface: &str) -> Self {
+        Self {
+            backend: network.backend_for(iface).clone(),
+            routes: network.routes.clone(),
+            properties: network.link_properties.clone(),
+        }
+    }
+}
+
+// brings up the configured VLANs, re-reads the route table and brings the namespace in
+// line with it: interfaces that gained a subnet and a default route get a link, those that
+// lost them (or moved to another subnet, or whose link settings changed) have theirs
+// removed, and are set up again if they still qualify. VLANs are relayed by us and
+// get no link. interface matching and route options use the new table right away.
+// an interface that fails is reported in the changes (its new links are removed again)
+// and the others are synced all the same.
+pub fn sync(state: &State, ip: Ipv4Addr) -> io::Result<Vec<String>> {
+    let network = state.config.read().unwrap().network.clone();
+    sync_network(state, &network, ip)
+}
+
+// `sync` with `network` in place of the running config, for a reload to apply before
+// swapping its config in
+pub(crate) fn sync_network(
+    state: &State,
+    network: &NetworkConfig,
+    ip: Ipv4Addr,
+) -> io::Result<Vec<String>> {
+    // the table is read before taking the lock, so a bad one cannot leave it poisoned
+    let mut route_info = HashMap::new();
+    for r in new_route(network.route_path())? {
+        r.parse_network(&mut route_info)?;
+    }
+    // one sync at a time; `attached` is only locked to read and update it. the lock
+    // guards no data, so a sync that panicked does not keep the next ones out
+    let _syncing = state.syncing.lock().unwrap_or_else(PoisonError::into_inner);
+    let (created, vlans, mut changes) = vlan::setup(&network.vlans);
+    state.resources.lock().unwrap().extend(created);
+    *state.vlans.write().unwrap() = vlans;
+    vlan::merge(&network.vlans, &mut route_info);
+    let wanted: HashMap<String, Ipv4Net> = route_info
+        .iter()
+        .filter(|(k, v)| v.is_full() && network.vlan(k).is_none())
+        .filter_map(|(k, v)| Some((k.clone(), subnet_for(v, ip)?)))
+        .collect();
+    *state.route_info.write().unwrap() = route_info.clone();
+
+    let stale: Vec<(String, Attached)> = state
+        .attached
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(k, a)| wanted.get(*k) != Some(&a.subnet) || a.setup != LinkSetup::new(network, k))
+        .map(|(k, a)| (k.clone(), a.clone()))
+        .collect();
+    for (iface, a) in stale {
+        let del = Step::DelLink {
+            link: a.link.clone(),
+            netns: a.netns.clone(),
+        };
+        // still attached; tried again with the next sync
+        if let Err(e) = del.run() {
+            warn!("could not detach {} (link {}): {}", iface, a.link, e);
+            changes.push(format!("{}: detach failed: {}", iface, e));
+            continue;
+        }
+        state.attached.lock().unwrap().remove(&iface);
+        // addresses and routes go with the link; the namespace and rules stay
+        state.resources.lock().unwrap().retain(|r| {
+            matches!(r, Resource::Netns(_) | Resource::Rule { .. }) || !a.resources.contains(r)
+        });
+        info!("detached {} (link {})", iface, a.link);
+        changes.push(format!("{}: detached", iface));
+    }
+    let mut added: Vec<(String, Ipv4Net)> = {
+        let attached = state.attached.lock().unwrap();
+        wanted
+            .into_iter()
+            .filter(|(k, _)| !attached.contains_key(k))
+            .collect()
+    };
+    added.sort();
+    for (iface, subnet) in added {
+        let link = {
+            let attached = state.attached.lock().unwrap();
+            let taken: Vec<&str> = attached.values().map(|a| a.link.as_str()).collect();
+            link_name(&network.link, &taken)
+        };
+        let created = match setup_ns(
+            link.as_str(),
+            iface.as_str(),
+            ip,
+            &route_info[&iface],
+            network,
+        ) {
+            Ok(created) => created,
+            Err(e) => {
+                warn!("could not attach {}: {}", iface, e);
+                changes.push(format!("{}: attach failed: {}", iface, e));
+                continue;
+            }
+        };
+        // a rule in a namespace that is not ours comes with each link
+        let mut resources = state.resources.lock().unwrap();
+        for r in &created {
+            if !resources.contains(r) {
+                resources.push(r.clone());
+            }
+        }
+        drop(resources);
+        let netns = match network.backend_for(&iface) {
+            LinkBackend::Veth | LinkBackend::Bridge(_) => None,
+            LinkBackend::Macvlan | LinkBackend::Ipvlan => Some(network.netns.to_string()),
+        };
+        let setup = LinkSetup::new(network, &iface);
+        info!("attached {} via {} ({})", iface, link, subnet);
+        changes.push(format!("{}: attached via {}", iface, link));
+        state.attached.lock().unwrap().insert(
+            iface,
+            Attached {
+                link,
+                netns,
+                subnet,
+                resources: created,
+                setup,
+            },
+        );
+    }
+    changes.extend(sync_agents(state));
+    Ok(changes)
+}
+
+// the relay agent address of each attached interface: its own address in the subnet the
+// server is on. set as giaddr on requests arriving on the interface, so it follows the
+// interface's addresses.
+fn sync_agents(state: &State) -> Vec<String> {
+    let attached: Vec<(String, Ipv4Net)> = state
+        .attached
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(k, a)| (k.clone(), a.subnet))
+        .collect();
+    let mut agents = HashMap::new();
+    for (iface, subnet) in attached {
+        let address = link_addresses(&iface)
+            .unwrap_or_default()
+            .into_iter()
+            .find(|a| subnet.contains(a));
+        match (if_nametoindex(iface.as_str()), address) {
+            (Ok(index), Some(address)) => {
+                agents.insert(
+                    index,
+                    Agent {
+                        name: iface,
+                        address,
+                    },
+                );
+            }
+            _ => debug!("no relay agent address for {} in {}", iface, subnet),
+        }
+    }
+    let mut current = state.agents.write().unwrap();
+    let mut changes: Vec<String> = agents
+        .values()
+        .filter(|a| !current.values().any(|c| c == *a))
+        .map(|a| format!("{}: giaddr {}", a.name, a.address))
+        .collect();
+    changes.sort();
+    *current = agents;
+    changes
+}
+
+// the subnet setup_ns picks for the server address
+pub(crate) fn subnet_for(route_info: &RouteInfo, ip: Ipv4Addr) -> Option<Ipv4Net> {
+    route_info.subnet_for(ip).or_else(|| route_info.subnet())
+}
+
+// `link` for the first interface, `link-1`, `link-2`, ... for the others
+pub(crate) fn link_name(base: &str, taken: &[&str]) -> String {
+    let used = |name: &str| taken.contains(&name);
+    if !used(base) {
+        return base.to_string();
+    }
+    (1..)
+        .map(|n| format!("{}-{}", base, n))
+        .find(|name| !used(name))
+        .unwrap()
+}
+
+// follows the route source and calls `sync` after each change
+pub async fn watch(state: Arc<State>, ip: Ipv4Addr) {
+    let network = state.config.read().unwrap().network.clone();
+    if !network.watch {
+        return;
+    }
+    let notify = Arc::new(Notify::new());
+    let changed = Arc::clone(&notify);
+    let path = network.route_file.clone();
+    match network.route_source {
+        RouteSource::File => {
+            // a plain thread, as the runtime would wait for a blocking task on shutdown
+            thread::spawn(move || {
+                if let Err(e) = watch_file(&path, &changed) {
+                    warn!("(watch) stopped following {}: {}", path.display(), e);
+                }
+            });
+        }
+        RouteSource::Netlink => {
+            tokio::spawn(async move {
+                if let Err(e) = watch_netlink(changed).await {
+                    warn!("(watch) stopped following netlink: {}", e);
+                }
+            });
+        }
+    }
+    loop {
+        notify.notified().await;
+        tokio::time::sleep(SETTLE).await;
+        let state = Arc::clone(&state);
+        match tokio::task::spawn_blocking(move || sync(&state, ip)).await {
+            Ok(Ok(changes)) if changes.is_empty() => {
+                debug!("(watch) routes changed; no links to update")
+            }
+            Ok(Ok(changes)) => info!("(watch) {}", changes.join(", ")),
+            Ok(Err(e)) => warn!("(watch) could not apply route changes: {}", e),
+            Err(e) => warn!("(watch) sync failed: {}", e),
+        }
+    }
+}
+
+const FILE_EVENTS: AddWatchFlags = AddWatchFlags::IN_MODIFY
+    .union(AddWatchFlags::IN_CLOSE_WRITE)
+    .union(AddWatchFlags::IN_MOVE_SELF)
+    .union(AddWatchFlags::IN_DELETE_SELF);
+
+// blocks on inotify; runs on its own thread
+pub(crate) fn watch_file(path: &Path, changed: &Notify) -> io::Result<()> {
+    let inotify = Inotify::init(InitFlags::IN_CLOEXEC)?;
+    inotify.add_watch(path, FILE_EVENTS)?;
+    info!("(watch) following {}", path.display());
+    loop {
+        let events = match inotify.read_events() {
+            Ok(events) => events,
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        changed.notify_one();
+        // the file was replaced or removed; watch whatever shows up at the path next
+        if events
+            .iter()
+            .any(|e| e.mask.contains(AddWatchFlags::IN_IGNORED))
+        {
+            while let Err(e) = inotify.add_watch(path, FILE_EVENTS) {
+                debug!("(watch) waiting for {}: {}", path.display(), e);
+                thread::sleep(RETRY_INTERVAL);
+            }
+            changed.notify_one();
+        }
+    }
+}
+
+// RTM_NEWLINK/DELLINK, RTM_NEWADDR/DELADDR and RTM_NEWROUTE/DELROUTE of the current namespace
+async fn watch_netlink(changed: Arc<Notify>) -> io::Result<()> {
+    let (mut connection, _, mut messages) = new_connection()?;
+    let groups = RTMGRP_LINK | RTMGRP_IPV4_IFADDR | RTMGRP_IPV4_ROUTE;
+    connection
+        .socket_mut()
+        .socket_mut()
+        .bind(&SocketAddr::new(0, groups))?;
+    tokio::spawn(connection);
+    info!("(watch) following netlink notifications");
+    while let Some((msg, _)) = messages.next().await {
+        debug!("(watch) netlink: {:?}", msg.payload);
+        changed.notify_one();
+    }
+    Ok(())
+}